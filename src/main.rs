@@ -1,214 +1,6469 @@
-#![feature(simd_ffi)]
+#![cfg_attr(target_arch = "wasm32", feature(simd_ffi))]
 
-use std::cell::RefCell;
+mod simd;
+mod stable_alloc;
 
-use candid::candid_method;
-use ic_cdk::api::stable::{stable_grow, stable_read, stable_write};
-use ic_cdk_macros::{init, update};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
 
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use serde::Deserialize;
+use simd::*;
+use stable::{stable_grow, stable_read, stable_size, stable_write};
+use stable_alloc::StableAlloc;
+
+#[derive(Clone)]
 struct Data {
     a: Vec<i32>,
     b: Vec<i32>,
+    // `out` is the published result that queries read. Multiplies write into
+    // `out_back` and only swap it in once every element has been computed, so
+    // a reader never observes a partially written vector.
     out: Vec<i32>,
+    out_back: Vec<i32>,
+    // Added to each output by every multiply of `A·b`, on the heap or in
+    // stable memory. Zero unless set, and mirrored into stable memory.
+    bias: Vec<i32>,
+    // The length-`d` vector multiplied by `multiply_transpose`, and its
+    // length-`n` result. Heap only. `init`, `reconfigure` and `init_tiled` set
+    // `x` to `0..d`; other changes of matrix, which go through `configure`,
+    // and upgrades reset it to zero.
+    x: Vec<i32>,
+    out_transpose: Vec<i32>,
+    // Where the last multiply left its output, and so where `get_output`
+    // reads it from.
+    last_path: OutputPath,
+    // Number of multiplies served, for telemetry. Kept across upgrades.
+    call_count: u64,
+}
+
+impl Data {
+    fn publish_output(&mut self) {
+        std::mem::swap(&mut self.out, &mut self.out_back);
+        self.last_path = OutputPath::Heap;
+        output_changed();
+    }
+
+    /// The output of the last multiply, from wherever it wrote it: the heap
+    /// output, or the stable `out` region. Everything that reads the output
+    /// goes through here.
+    fn current_output(&self) -> Cow<'_, [i32]> {
+        match self.last_path {
+            OutputPath::Heap => Cow::Borrowed(&self.out),
+            OutputPath::Stable => Cow::Owned(read_stable_i32s(
+                Layout::current_of(self).out_addr(),
+                self.out.len(),
+            )),
+        }
+    }
+}
+
+/// Where a multiply writes its output: `out` on the heap, or the stable `out`
+/// region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputPath {
+    Heap,
+    Stable,
+}
+
+impl OutputPath {
+    fn name(self) -> &'static str {
+        match self {
+            OutputPath::Heap => "heap",
+            OutputPath::Stable => "stable",
+        }
+    }
+}
+
+/// Records that a multiply has written the stable `out` region.
+fn published_to_stable() {
+    DATA.with(|data| data.borrow_mut().last_path = OutputPath::Stable);
+    output_changed();
+}
+
+fn output_changed() {
+    OUTPUT_GENERATION.with(|generation| generation.set(generation.get() + 1));
 }
 
 thread_local! {
     pub static DATA: RefCell<Data> =
-      RefCell::new(Data {
+      const { RefCell::new(Data {
         a: Vec::new(),
         b: Vec::new(),
-        out: Vec::new()}
-    );
+        out: Vec::new(),
+        out_back: Vec::new(),
+        bias: Vec::new(),
+        x: Vec::new(),
+        out_transpose: Vec::new(),
+        last_path: OutputPath::Heap,
+        call_count: 0}
+    ) };
+
+    // Snapshots of previously computed matrices, so the output of one layer
+    // can be fed into the next without a round trip through the client.
+    static NAMED: RefCell<BTreeMap<String, Named>> = const { RefCell::new(BTreeMap::new()) };
+
+    // Hands out the stable regions of the named matrices, past the layout.
+    static NAMED_ALLOC: RefCell<StableAlloc> = const { RefCell::new(StableAlloc::new()) };
+
+    pub static DATA_F32: RefCell<FloatData> = const {
+        RefCell::new(FloatData {
+            a: Vec::new(),
+            b: Vec::new(),
+            out: Vec::new(),
+        })
+    };
+
+    pub static DATA_I64: RefCell<WideData> = const {
+        RefCell::new(WideData {
+            a: Vec::new(),
+            b: Vec::new(),
+            out: Vec::new(),
+        })
+    };
+
+    static DATA_I8_F32: RefCell<DequantData> = const {
+        RefCell::new(DequantData {
+            a: Vec::new(),
+            scale: 1.0,
+            b: Vec::new(),
+        })
+    };
+
+    static DATA_INT8: RefCell<Int8Data> = const {
+        RefCell::new(Int8Data {
+            a: Vec::new(),
+            b: Vec::new(),
+            za: 0,
+            zb: 0,
+        })
+    };
+
+    // `A` as packed by `pack_ternary_a`, with its shape.
+    static TERNARY_A: RefCell<TernaryA> = const {
+        RefCell::new(TernaryA {
+            packed: Vec::new(),
+            n: 0,
+            d: 0,
+        })
+    };
+
+    // The runs stored by `init_rle`, if any.
+    static RLE_A: RefCell<Option<RleA>> = const { RefCell::new(None) };
+
+    // `b` as loaded by `prepare_b_simd`, reused by `multiply_stable` until `b`
+    // changes.
+    static PREPARED_B: RefCell<Option<PreparedB>> = const { RefCell::new(None) };
+
+    static ERROR_MODE: Cell<ErrorMode> = const { Cell::new(ErrorMode::Trap) };
+
+    static VERBOSE: Cell<bool> = const { Cell::new(false) };
+
+    // The buffer `multiply_active` multiplies.
+    static ACTIVE_DTYPE: Cell<DType> = const { Cell::new(DType::I32) };
+
+    // The next row `multiply_heap_progressive` computes.
+    static PROGRESSIVE_ROW: Cell<u64> = const { Cell::new(0) };
+
+    // The next row `multiply_stable_bounded` computes.
+    static BOUNDED_ROW: Cell<u64> = const { Cell::new(0) };
+
+    // Bumped whenever the output changes, so `output_cursor` can tell when it
+    // would be reading a different output from the one it started on.
+    static OUTPUT_GENERATION: Cell<u64> = const { Cell::new(0) };
+
+    // `multiply_stable` uses its scalar loop for `n` below this.
+    static SIMD_THRESHOLD: Cell<usize> = const { Cell::new(0) };
+
+    // Set by `cancel`, and cleared by the `multiply_heap_cancellable` it stops.
+    static CANCELLED: Cell<bool> = const { Cell::new(false) };
+
+    // Lines printed by `logged`, so tests can see whether logging is on.
+    #[cfg(test)]
+    static LOG_LINES: Cell<u64> = const { Cell::new(0) };
+
+    // A row before which `multiply_heap_cancellable` calls `cancel` itself,
+    // standing in for a cancellation arriving mid-multiply.
+    #[cfg(test)]
+    static CANCEL_AT_ROW: Cell<Option<u64>> = const { Cell::new(None) };
 }
 
-#[cfg(target_arch = "wasm32")]
-pub mod ic0 {
-    #[link(wasm_import_module = "ic0")]
-    extern "C" {
-        pub fn stable_read_v128(src: u64) -> core::arch::wasm32::v128;
-        pub fn stable_write_i32(dst: u64, val: i32);
-    }
+/// Stable memory usage, as reported by `memory_stats`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes in the allocated stable pages, which never decreases.
+    stable_allocated_bytes: u64,
+    /// Bytes from the start of stable memory to the end of the current layout,
+    /// or of the last named matrix or `init_rle` runs stored after it.
+    stable_used_bytes: u64,
 }
 
-#[candid_method(init)]
-#[init]
-fn init(n: usize, d: usize) {
-    DATA.with(|data| {
-        let mut data = data.borrow_mut();
-        data.a.reserve(n * d);
-        for i in 0..n * d {
-            data.a.push(i as u32 as i32);
-        }
+/// What `init` of a shape would allocate, as reported by `init_cost`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitCost {
+    /// Stable pages the layout needs, and at least one.
+    stable_pages: u64,
+    /// Bytes of the heap copies of the matrices, the outputs, the bias and
+    /// `x`.
+    heap_bytes: u64,
+}
 
-        data.b.reserve(n);
-        for i in 0..n {
-            data.b.push(i as u32 as i32);
-        }
+/// Element types of the matrices the canister holds.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DType {
+    I32,
+    F32,
+    I64,
+}
 
-        data.out = vec![0; d];
+/// How the rows of `A` are laid out.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOrder {
+    /// Each row's `n` elements are contiguous, one row after another.
+    RowMajor,
+}
 
-        let stable_pages = ((n * d + n + d) * 4) / (64 * 1024) + 1;
-        stable_grow(stable_pages as u64).unwrap();
-        for i in 0..n * d {
-            let val = data.a[i].to_le_bytes();
-            stable_write((i * 4) as u64, &val);
-        }
-        for i in 0..n {
-            let val = data.b[i].to_le_bytes();
-            stable_write((n * d * 4 + i * 4) as u64, &val);
-        }
-    });
+/// The configuration of the `i32` matrix, as returned by `describe`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Description {
+    n: u64,
+    d: u64,
+    dtype: DType,
+    /// Whether `init` (or anything else that sets the shape) has run.
+    initialized: bool,
+    storage_order: StorageOrder,
 }
 
-#[cfg(target_arch = "wasm32")]
-#[candid_method(update)]
-#[update]
-pub fn multiply_stable() {
-    use core::arch::wasm32::*;
+/// The current inputs and their product, as returned by `export_test_vector`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TestVector {
+    n: u64,
+    d: u64,
+    /// `A`, row by row.
+    a: Vec<i32>,
+    b: Vec<i32>,
+    /// `A·b`, wrapping on overflow.
+    expected_out: Vec<i32>,
+}
 
-    let (n, d) = DATA.with(|data| {
-        let data = data.borrow();
-        (data.b.len() as u64, data.out.len() as u64)
-    });
+/// How `multiply_stable` would run for the current shape, as returned by
+/// `plan`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Plan {
+    /// Size of one element of `A` and `b`.
+    element_bytes: u32,
+    /// Stable memory reads (host calls) across all rows.
+    host_reads: u64,
+    /// `v128` multiply-accumulates across all rows.
+    simd_iterations: u64,
+    /// Elements handled one at a time, after the last whole `v128` of a row.
+    scalar_tail: u64,
+}
 
-    let a_addr = 0;
-    let b_addr = n * d * 4;
-    let out_addr = (n * d + n) * 4;
+/// How the multiply methods report a `MatMulError`.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Trap with the error's message, failing the call. The default.
+    Trap,
+    /// Return the error to the caller.
+    Result,
+}
 
-    for i in 0..d {
-        let in_ = i * n * 4;
-        let mut vals = i32x4(0, 0, 0, 0);
-        for j in (0..n).step_by(4) {
-            let a_group: v128 = unsafe { ic0::stable_read_v128(a_addr + in_ + j * 4) };
-            let b_group: v128 = unsafe { ic0::stable_read_v128(b_addr + j * 4) };
-            vals = i32x4_add(vals, i32x4_mul(a_group, b_group));
+/// What `multiply_heap_mode` does with a dot product outside the `i32` range.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Keep the low 32 bits, like `multiply_heap`.
+    Wrap,
+    /// Clamp to `i32::MIN` or `i32::MAX`.
+    Saturate,
+    /// Trap.
+    Checked,
+}
+
+/// A matrix whose dimensions the requested multiply can't handle.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MatMulError {
+    /// `A` has `a_len` elements rather than `n * d`.
+    ShapeMismatch { a_len: u64, n: u64, d: u64 },
+    /// The kernel consumes `b` in groups of `group` elements, and `n` isn't a
+    /// multiple of that.
+    UnsupportedLength { n: u64, group: u64 },
+    /// The layout needs `needed` bytes of stable memory, but only `allocated`
+    /// are.
+    StableMemoryTooSmall { needed: u64, allocated: u64 },
+    /// A `d × n` matrix has more elements than fit in memory, or its layout
+    /// needs more than the maximum stable memory.
+    ShapeTooLarge { n: u64, d: u64 },
+}
+
+impl fmt::Display for MatMulError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatMulError::ShapeMismatch { a_len, n, d } => write!(
+                f,
+                "A has {} elements, but n = {}, d = {} needs {}",
+                a_len,
+                n,
+                d,
+                n * d
+            ),
+            MatMulError::UnsupportedLength { n, group } => {
+                write!(f, "n = {} is not a multiple of {}", n, group)
+            }
+            MatMulError::StableMemoryTooSmall { needed, allocated } => write!(
+                f,
+                "needs {} bytes of stable memory, but only {} are allocated",
+                needed, allocated
+            ),
+            MatMulError::ShapeTooLarge { n, d } => {
+                write!(f, "n = {}, d = {} is too large", n, d)
+            }
         }
-        let val = i32x4_extract_lane::<0>(vals)
-            + i32x4_extract_lane::<1>(vals)
-            + i32x4_extract_lane::<2>(vals)
-            + i32x4_extract_lane::<3>(vals);
-        unsafe { ic0::stable_write_i32(out_addr + i * 4, val) };
     }
 }
 
-#[candid_method(update)]
-#[update]
-pub fn multiply_stable_old() {
-    let (n, d) = DATA.with(|data| {
-        let data = data.borrow();
-        (data.b.len() as u64, data.out.len() as u64)
-    });
+/// A matrix saved by `save_matrix`. Its `A`, `b` and output are also copied to
+/// their own region of stable memory, `len` bytes at `addr`, after the
+/// layout.
+struct Named {
+    data: Data,
+    addr: u64,
+    len: u64,
+}
 
-    let a_addr = 0;
-    let b_addr = n * d * 4;
-    let out_addr = (n * d + n) * 4;
-    // let mut a_group = Box::new(i32x4(0, 0, 0, 0));
-    // let mut b_group = Box::new(i32x4(0, 0, 0, 0));
-    let mut a_group = [0_u8; 16];
-    let mut b_group = [0_u8; 16];
+/// The `f32` counterpart of the heap part of `Data`, for floating-point
+/// experiments. It is independent of the `i32` matrix and isn't mirrored into
+/// stable memory.
+struct FloatData {
+    a: Vec<f32>,
+    b: Vec<f32>,
+    out: Vec<f32>,
+}
 
-    for i in 0..d {
-        let in_ = i * n * 4;
-        let mut val = 0;
-        for j in (0..n).step_by(4) {
-            stable_read(a_addr + in_ + j * 4, &mut a_group);
-            stable_read(b_addr + j * 4, &mut b_group);
-            let a_group: [i32; 4] = unsafe { std::mem::transmute(a_group) };
-            let b_group: [i32; 4] = unsafe { std::mem::transmute(b_group) };
-            let a_group = a_group.as_ptr();
-            let b_group = b_group.as_ptr();
+/// The `i64` counterpart of the heap part of `Data`, for inputs whose products
+/// don't fit an `i64`. Like `FloatData`, it lives only on the heap.
+struct WideData {
+    a: Vec<i64>,
+    b: Vec<i64>,
+    out: Vec<i64>,
+}
 
-            let mut ival: i32 = 0;
-            for i in 0..4 {
-                ival += unsafe { *a_group.add(i) * *b_group.add(i) };
-            }
+/// Quantized `i8` weights `A` with their scale, and `f32` activations `b`,
+/// multiplied by `multiply_i8_f32`. Like `FloatData`, it lives only on the
+/// heap.
+struct DequantData {
+    a: Vec<i8>,
+    scale: f32,
+    b: Vec<f32>,
+}
+
+/// Asymmetrically quantized `i8` inputs with their zero points, multiplied by
+/// `multiply_int8`. Like `FloatData`, it lives only on the heap.
+struct Int8Data {
+    a: Vec<i8>,
+    b: Vec<i8>,
+    za: i32,
+    zb: i32,
+}
+
+/// A ternary `A`, four weights to a byte, for `multiply_ternary`. Weight `k`
+/// of the row-major matrix is bits `2 * (k % 4)` and up of `packed[k / 4]`:
+/// `0b00` for 0, `0b01` for 1 and `0b11` for -1.
+struct TernaryA {
+    packed: Vec<u8>,
+    n: usize,
+    d: usize,
+}
+
+/// Where `init_rle` put the runs of a run-length encoded `A` in stable
+/// memory: twelve bytes per run, a little-endian `i32` value and then a `u64`
+/// count.
+struct RleA {
+    addr: u64,
+    runs: u64,
+    n: usize,
+    d: usize,
+}
+
+struct PreparedB {
+    groups: Vec<v128>,
+    // The last `n % 4` elements, which don't fill a group.
+    tail: Vec<i32>,
+}
+
+fn invalidate_prepared_b() {
+    PREPARED_B.with(|prepared| *prepared.borrow_mut() = None);
+}
+
+/// Restarts `multiply_heap_progressive` and `multiply_stable_bounded`, whose
+/// earlier rows are stale once `A`, `b` or the bias changes.
+fn restart_partial_multiplies() {
+    PROGRESSIVE_ROW.with(|row| row.set(0));
+    BOUNDED_ROW.with(|row| row.set(0));
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// The largest `A`, in bytes, for which `recommended_path` suggests the heap.
+const HEAP_PATH_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+// The documented maximum size of a canister's stable memory, 400 GiB.
+const MAX_STABLE_BYTES: u64 = 400 * 1024 * 1024 * 1024;
+
+// `n`, `d` and the call count.
+const META_LEN: u64 = 3 * 8;
+
+/// Byte offsets of the regions kept in stable memory for `d` rows of `n`
+/// elements: `A` row by row, then `b`, the `i32` output, the bias, the `i64`
+/// output, the output snapshot, each as little-endian values, and finally the
+/// metadata saved across upgrades.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Layout {
+    n: u64,
+    d: u64,
+}
+
+impl Layout {
+    fn new(n: u64, d: u64) -> Self {
+        Self { n, d }
+    }
+
+    /// The layout of the current matrix.
+    fn current() -> Self {
+        DATA.with(|data| Self::current_of(&data.borrow()))
+    }
+
+    fn current_of(data: &Data) -> Self {
+        Self::new(data.b.len() as u64, data.out.len() as u64)
+    }
+
+    fn a_addr(&self) -> u64 {
+        0
+    }
+
+    fn b_addr(&self) -> u64 {
+        self.n * self.d * 4
+    }
+
+    fn out_addr(&self) -> u64 {
+        self.b_addr() + self.n * 4
+    }
+
+    fn bias_addr(&self) -> u64 {
+        self.out_addr() + self.d * 4
+    }
+
+    fn out_i64_addr(&self) -> u64 {
+        self.bias_addr() + self.d * 4
+    }
+
+    /// Where `snapshot_output` copies the `i32` output.
+    fn snapshot_addr(&self) -> u64 {
+        self.out_i64_addr() + self.d * 8
+    }
+
+    /// Where `pre_upgrade` saves `n`, `d` and the call count, as `u64`s.
+    fn meta_addr(&self) -> u64 {
+        self.snapshot_addr() + self.d * 4
+    }
+
+    /// Total bytes used by the layout.
+    fn size(&self) -> u64 {
+        self.meta_addr() + META_LEN
+    }
+
+    /// Pages needed to hold the layout, and at least one.
+    fn pages(&self) -> u64 {
+        self.size().div_ceil(WASM_PAGE_SIZE).max(1)
+    }
+
+    fn regions(&self) -> [Region; 7] {
+        [
+            Region::new("a", self.a_addr(), self.n * self.d * 4),
+            Region::new("b", self.b_addr(), self.n * 4),
+            Region::new("out", self.out_addr(), self.d * 4),
+            Region::new("bias", self.bias_addr(), self.d * 4),
+            Region::new("out_i64", self.out_i64_addr(), self.d * 8),
+            Region::new("snapshot", self.snapshot_addr(), self.d * 4),
+            Region::new("meta", self.meta_addr(), META_LEN),
+        ]
+    }
+}
+
+/// A named byte range of stable memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Region {
+    name: &'static str,
+    start: u64,
+    len: u64,
+}
+
+impl Region {
+    fn new(name: &'static str, start: u64, len: u64) -> Self {
+        Self { name, start, len }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
 
-            val += ival;
+    /// Whether the two share a byte. An empty region overlaps nothing.
+    fn overlaps(&self, other: &Region) -> bool {
+        self.len > 0 && other.len > 0 && self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// Panics if any two `regions` overlap or any ends past `allocated` bytes. A
+/// layout bug would otherwise silently corrupt one region from another. Only
+/// checked in debug builds.
+fn check_regions(regions: &[Region], allocated: u64) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    for (i, region) in regions.iter().enumerate() {
+        assert!(
+            region.end() <= allocated,
+            "stable region {} [{}, {}) exceeds the {} allocated bytes",
+            region.name,
+            region.start,
+            region.end(),
+            allocated
+        );
+        for other in &regions[i + 1..] {
+            assert!(
+                !region.overlaps(other),
+                "stable region {} [{}, {}) overlaps {} [{}, {})",
+                region.name,
+                region.start,
+                region.end(),
+                other.name,
+                other.start,
+                other.end()
+            );
         }
-        stable_write(out_addr + i * 4, &val.to_le_bytes());
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+mod stable {
+    pub use ic_cdk::api::stable::{stable_grow, stable_read, stable_size, stable_write};
+}
+
+// Natively (i.e. under `cargo test`) there is no canister stable memory, so
+// it is emulated with a heap buffer that has the same paging and bounds
+// behavior.
 #[cfg(not(target_arch = "wasm32"))]
-#[candid_method(update)]
-#[update]
-pub fn multiply_stable() {}
+mod stable {
+    use std::cell::{Cell, RefCell};
 
-#[candid_method(update)]
-#[update]
-pub fn update_empty() {}
+    use ic_cdk::api::stable::StableMemoryError;
 
-pub fn matmul<const GROUP_SIZE: usize>() {
-    DATA.with(|data| {
-        let mut data = data.borrow_mut();
+    use crate::WASM_PAGE_SIZE;
 
-        let n = data.b.len();
-        let d = data.out.len();
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static READS: Cell<u64> = const { Cell::new(0) };
+        static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+    }
 
-        assert_eq!(data.a.len(), n * d);
+    // Rough cost of a stable memory host call: a fixed overhead plus one
+    // instruction per byte copied.
+    const HOST_CALL_INSTRUCTIONS: u64 = 100;
 
-        for i in 0..d {
-            let in_ = i * n;
-            let mut val = 0;
+    fn charge(bytes: usize) {
+        INSTRUCTIONS.with(|count| count.set(count.get() + HOST_CALL_INSTRUCTIONS + bytes as u64));
+    }
 
-            // matmul in groups of `GROUP_SIZE`.
-            for j in (0..n).step_by(GROUP_SIZE) {
-                // NOTE: Using raw pointer arithmetic as it reduces the overhead of bound checks.
-                // Expeirments showed that it reduces instructions by 6.6%.
-                unsafe {
-                    let b_group = data.b.as_ptr().add(j);
-                    let a_group = data.a.as_ptr().add(in_ + j);
+    /// Stands in for the instruction counter, counting only stable memory
+    /// accesses.
+    pub fn instruction_counter() -> u64 {
+        INSTRUCTIONS.with(|count| count.get())
+    }
 
-                    // multiply and sum both groups.
-                    let mut ival: i32 = 0;
-                    for i in 0..GROUP_SIZE {
-                        ival += *a_group.add(i) as i32 * *b_group.add(i) as i32;
-                    }
+    /// Zeroes the instruction counter, as at the start of a new message.
+    #[cfg(test)]
+    pub fn reset_instruction_counter() {
+        INSTRUCTIONS.with(|count| count.set(0));
+    }
 
-                    val += ival;
-                }
-            }
-            unsafe { *data.out.as_mut_ptr().add(i) = val };
-        }
-    });
+    /// The number of stable reads (host calls) made so far.
+    #[cfg(test)]
+    pub fn read_count() -> u64 {
+        READS.with(|reads| reads.get())
+    }
+
+    pub fn stable_size() -> u64 {
+        MEMORY.with(|memory| memory.borrow().len() as u64 / WASM_PAGE_SIZE)
+    }
+
+    pub fn stable_grow(new_pages: u64) -> Result<u64, StableMemoryError> {
+        MEMORY.with(|memory| {
+            let mut memory = memory.borrow_mut();
+            let old_pages = memory.len() as u64 / WASM_PAGE_SIZE;
+            memory.resize(((old_pages + new_pages) * WASM_PAGE_SIZE) as usize, 0);
+            Ok(old_pages)
+        })
+    }
+
+    pub fn stable_write(offset: u64, buf: &[u8]) {
+        charge(buf.len());
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            memory.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        })
+    }
+
+    pub fn stable_read(offset: u64, buf: &mut [u8]) {
+        READS.with(|reads| reads.set(reads.get() + 1));
+        charge(buf.len());
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            buf.copy_from_slice(&memory.borrow()[offset..offset + buf.len()]);
+        })
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub mod ic0 {
+    #[link(wasm_import_module = "ic0")]
+    extern "C" {
+        pub fn stable_read_v128(src: u64) -> core::arch::wasm32::v128;
+        pub fn stable_write_i32(dst: u64, val: i32);
+    }
+}
+
+// Emulation of the system API above on top of the emulated stable memory. Like
+// the replica, `stable_read_v128` rejects addresses that aren't 16-byte
+// aligned.
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(clippy::missing_safety_doc)]
+pub mod ic0 {
+    use crate::simd::v128;
+    use crate::stable::{stable_read, stable_write};
+
+    pub unsafe fn stable_read_v128(src: u64) -> v128 {
+        assert!(
+            src.is_multiple_of(16),
+            "stable_read_v128 of unaligned address {}",
+            src
+        );
+        let mut bytes = [0; 16];
+        stable_read(src, &mut bytes);
+        crate::v128_from_le_bytes(bytes)
+    }
+
+    pub unsafe fn stable_write_i32(dst: u64, val: i32) {
+        stable_write(dst, &val.to_le_bytes());
+    }
+}
+
+/// Sets up `b` with `n` elements and `A` with `d` rows of `n` elements, both
+/// filled with ascending values and mirrored into stable memory, and `x` with
+/// `d` ascending values for `multiply_transpose`.
+///
+/// Either dimension may be zero. With `n = 0` every output is the empty dot
+/// product, `0`, and with `d = 0` the output is empty; no multiply traps in
+/// either case. Stable memory always gets at least one page.
+#[candid_method(init)]
+#[init]
+fn init(n: usize, d: usize) {
+    trap_on_error(check_shape(n, d));
+    let a = (0..n * d).map(|i| i as u32 as i32).collect();
+    let b = (0..n).map(|i| i as u32 as i32).collect();
+    configure(d, a, b);
+    DATA.with(|data| data.borrow_mut().x = (0..d).map(|i| i as u32 as i32).collect());
 }
 
+/// Sets up a new shape like `init` does, after installation.
+///
+/// Shrinking to a smaller shape frees the stable memory past the new layout,
+/// which is where anything placed after the layout (such as the strided `b` of
+/// `multiply_stable_strided`) then goes. The pages themselves stay allocated,
+/// as stable memory can't shrink on the IC; `memory_stats` reports both.
 #[candid_method(update)]
 #[update]
-fn multiply_heap() {
-    matmul::<64>();
+fn reconfigure(n: usize, d: usize) -> Result<(), MatMulError> {
+    if let Err(err) = check_shape(n, d) {
+        return fail(err);
+    }
+    init(n, d);
+    Ok(())
 }
 
-// When run on native this prints the candid service definition of this
-// canister, from the methods annotated with `candid_method` above.
-//
-// Note that `cargo test` calls `main`, and `export_service` (which defines
-// `__export_service` in the current scope) needs to be called exactly once. So
-// in addition to `not(target_family = "wasm")` we have a `not(test)` guard here
-// to avoid calling `export_service`, which we need to call in the test below.
-#[cfg(not(any(target_family = "wasm", test)))]
-fn main() {
-    // The line below generates did types and service definition from the
-    // methods annotated with `candid_method` above. The definition is then
-    // obtained with `__export_service()`.
-    candid::export_service!();
-    std::print!("{}", __export_service());
+/// What `init(n, d)` would allocate, without allocating anything, so that a
+/// shape can be checked before committing to it. Stable memory never
+/// shrinks, so `init` grows it only to `stable_pages`, if it has fewer. Traps
+/// on a shape that `init` would reject.
+#[candid_method(query)]
+#[query]
+fn init_cost(n: usize, d: usize) -> InitCost {
+    trap_on_error(check_shape(n, d));
+    let (n, d) = (n as u64, d as u64);
+    // `A`, then `b` and `out_transpose`, then `out`, `out_back`, the bias
+    // and `x`.
+    let elems = n * d + 2 * n + 4 * d;
+    InitCost {
+        stable_pages: Layout::new(n, d).pages(),
+        heap_bytes: elems * 4,
+    }
 }
 
-#[cfg(any(target_family = "wasm", test))]
-fn main() {}
-
-#[test]
-fn check_candid_file() {
-    let did_path = match std::env::var("DID_PATH") {
-        Ok(v) => v,
-        Err(_e) => "matrix-multiply.did".to_string(),
+/// Checks that `n * d` elements can be allocated and that the stable layout
+/// of the shape can be computed without overflowing and fits in the maximum
+/// stable memory.
+fn check_shape(n: usize, d: usize) -> Result<(), MatMulError> {
+    let too_large = MatMulError::ShapeTooLarge {
+        n: n as u64,
+        d: d as u64,
     };
-    let candid = String::from_utf8(std::fs::read(did_path).unwrap()).unwrap();
+    if n.checked_mul(d).is_none() {
+        return Err(too_large);
+    }
+    // `A`, `b`, then five `i32`s' worth per row: the output, the bias, the
+    // `i64` output and the snapshot.
+    let (n, d) = (n as u64, d as u64);
+    let size = n
+        .checked_mul(d)
+        .and_then(|elems| elems.checked_add(n))
+        .and_then(|elems| elems.checked_add(d.checked_mul(5)?))
+        .and_then(|elems| elems.checked_mul(4))
+        .and_then(|bytes| bytes.checked_add(META_LEN));
+    match size {
+        Some(size) if size <= MAX_STABLE_BYTES => {
+            debug_assert_eq!(size, Layout::new(n, d).size());
+            Ok(())
+        }
+        _ => Err(too_large),
+    }
+}
 
-    // See comments in main above
-    candid::export_service!();
-    let expected = __export_service();
+/// Next value of the SplitMix64 generator, a small deterministic PRNG.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
 
-    assert_eq!(candid, expected);
+/// Refills `A` and `b` with pseudo-random values in `[-128, 128)` generated
+/// from `seed`, keeping the current shape. The same seed always produces the
+/// same data, so benchmarks can be repeated over different random inputs
+/// without a reinstall.
+#[candid_method(update)]
+#[update]
+fn reseed(seed: u64) {
+    let Layout { n, d } = Layout::current();
+    let mut state = seed;
+    let mut next = || (splitmix64(&mut state) >> 56) as u8 as i8 as i32;
+    let a = (0..n * d).map(|_| next()).collect();
+    let b = (0..n).map(|_| next()).collect();
+    configure(d as usize, a, b);
+}
+
+/// Sets up a new shape like `init` does, but fills `A` by repeating `tile`
+/// across it in row-major order, so `A[i][j]` is `tile[(i * n + j) %
+/// tile.len()]`. A tile whose length divides `n` repeats the same pattern in
+/// every row.
+#[candid_method(update)]
+#[update]
+fn init_tiled(n: usize, d: usize, tile: Vec<i32>) {
+    assert!(!tile.is_empty(), "tile must not be empty");
+    trap_on_error(check_shape(n, d));
+    let a = tile.iter().copied().cycle().take(n * d).collect();
+    let b = (0..n).map(|i| i as u32 as i32).collect();
+    configure(d, a, b);
+    DATA.with(|data| data.borrow_mut().x = (0..d).map(|i| i as u32 as i32).collect());
+}
+
+/// Replaces the current matrix with `a` (`d` rows of `b.len()` elements) and
+/// `b`, growing stable memory as needed and mirroring both into it.
+fn configure(d: usize, a: Vec<i32>, b: Vec<i32>) {
+    let n = b.len();
+    assert_eq!(a.len(), n * d);
+
+    let layout = Layout::new(n as u64, d as u64);
+    if let Some(start) = named_start() {
+        assert!(
+            layout.size() <= start,
+            "n = {}, d = {} would overwrite the named matrices from byte {}; drop them first",
+            n,
+            d,
+            start
+        );
+    }
+    let current_pages = stable_size();
+    if layout.pages() > current_pages {
+        stable_grow(layout.pages() - current_pages).unwrap();
+    }
+    assert!(
+        stable_size() * WASM_PAGE_SIZE >= layout.size(),
+        "{} stable pages don't hold the {} bytes of the layout",
+        stable_size(),
+        layout.size()
+    );
+    check_regions(&layout.regions(), stable_size() * WASM_PAGE_SIZE);
+    write_stable_i32s(layout.a_addr(), &a);
+    write_stable_i32s(layout.b_addr(), &b);
+    write_stable_i32s(layout.bias_addr(), &vec![0; d]);
+    write_stable_i32s(layout.snapshot_addr(), &vec![0; d]);
+    invalidate_prepared_b();
+    restart_partial_multiplies();
+    output_changed();
+
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        *data = Data {
+            a,
+            b,
+            out: vec![0; d],
+            out_back: vec![0; d],
+            bias: vec![0; d],
+            x: vec![0; d],
+            out_transpose: vec![0; n],
+            last_path: OutputPath::Heap,
+            call_count: data.call_count,
+        }
+    });
+}
+
+// Stable memory already holds `A` and `b`, so an upgrade only needs to save
+// the metadata that lives on the heap.
+#[pre_upgrade]
+fn pre_upgrade() {
+    let (layout, call_count) = DATA.with(|data| {
+        let data = data.borrow();
+        (Layout::current_of(&data), data.call_count)
+    });
+    let meta: Vec<u8> = [layout.n, layout.d, call_count]
+        .iter()
+        .flat_map(|val| val.to_le_bytes())
+        .collect();
+    stable_write(layout.meta_addr(), &meta);
+}
+
+/// Rebuilds the heap matrix from stable memory. The upgrade must pass the
+/// same `(n, d)` the canister was installed with. The last output isn't kept,
+/// so multiply again after upgrading.
+///
+/// Only `A`, `b`, the bias and the call count survive. Everything else that
+/// lives on the heap is dropped: the named matrices and `init_rle` runs, whose
+/// stable regions are then free for the next layout, the other element types'
+/// matrices, and settings such as the error mode, which goes back to `Trap`.
+#[post_upgrade]
+fn post_upgrade(n: usize, d: usize) {
+    let layout = Layout::new(n as u64, d as u64);
+    let mut meta = [0; META_LEN as usize];
+    stable_read(layout.meta_addr(), &mut meta);
+    let meta: Vec<u64> = meta
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    assert_eq!(
+        (meta[0], meta[1]),
+        (layout.n, layout.d),
+        "upgraded with n = {}, d = {}, but stable memory holds n = {}, d = {}",
+        n,
+        d,
+        meta[0],
+        meta[1]
+    );
+
+    let a = read_stable_i32s(layout.a_addr(), n * d);
+    let b = read_stable_i32s(layout.b_addr(), n);
+    let bias = read_stable_i32s(layout.bias_addr(), d);
+    DATA.with(|data| {
+        *data.borrow_mut() = Data {
+            a,
+            b,
+            out: vec![0; d],
+            out_back: vec![0; d],
+            bias,
+            x: vec![0; d],
+            out_transpose: vec![0; n],
+            last_path: OutputPath::Heap,
+            call_count: meta[2],
+        }
+    });
+    output_changed();
+    // A real upgrade starts from an empty heap anyway, but natively the
+    // thread-locals carry over, so forget what refers to stable memory past
+    // the layout, as well as the error mode.
+    NAMED.with(|named| named.borrow_mut().clear());
+    NAMED_ALLOC.with(|alloc| *alloc.borrow_mut() = StableAlloc::new());
+    RLE_A.with(|rle| *rle.borrow_mut() = None);
+    ERROR_MODE.with(|mode| mode.set(ErrorMode::Trap));
+}
+
+/// Rewrites `A`, `b` and the bias in stable memory from the heap matrix, for
+/// when the two have drifted apart.
+#[candid_method(update)]
+#[update]
+fn resync_stable() {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let layout = Layout::current_of(&data);
+        write_stable_i32s(layout.a_addr(), &data.a);
+        write_stable_i32s(layout.b_addr(), &data.b);
+        write_stable_i32s(layout.bias_addr(), &data.bias);
+    });
+}
+
+/// Zeroes every stable region of the current layout, leaving the heap matrix
+/// as it is, to exercise `resync_stable`.
+#[cfg(test)]
+fn corrupt_stable() {
+    let layout = Layout::current();
+    stable_write(layout.a_addr(), &vec![0; layout.size() as usize]);
+}
+
+fn count_multiply() {
+    DATA.with(|data| data.borrow_mut().call_count += 1);
+}
+
+/// Sets whether the multiply methods trap or return a `MatMulError` when the
+/// inputs' dimensions don't suit them. Only the multiplies that return a
+/// `Result` consult it; those returning their output, such as
+/// `multiply_heap_nnz`, or a count, such as `multiply_heap_cancellable`,
+/// always trap. Invalid arguments trap whatever the mode.
+#[candid_method(update)]
+#[update]
+fn set_error_mode(mode: ErrorMode) {
+    ERROR_MODE.with(|cell| cell.set(mode));
+}
+
+/// Traps with `message`. Natively, where `ic_cdk::trap` panics without the
+/// message, panics with it instead, so tests can tell traps apart.
+fn trap(message: &str) -> ! {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::trap(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    panic!("{}", message);
+}
+
+/// Reports `err` according to the current `ErrorMode`.
+fn fail(err: MatMulError) -> Result<(), MatMulError> {
+    match ERROR_MODE.with(Cell::get) {
+        ErrorMode::Trap => trap(&err.to_string()),
+        ErrorMode::Result => Err(err),
+    }
+}
+
+/// Checks that the heap matrix is consistent and can be multiplied by a kernel
+/// that consumes `b` in groups of `group` elements.
+fn check_heap_shape(group: u64) -> Result<(), MatMulError> {
+    let (a_len, n, d) = DATA.with(|data| {
+        let data = data.borrow();
+        (
+            data.a.len() as u64,
+            data.b.len() as u64,
+            data.out.len() as u64,
+        )
+    });
+    check_lengths(a_len, n, d, group)
+}
+
+/// Like `check_heap_shape`, but for the `f32` matrix.
+fn check_f32_shape() -> Result<(), MatMulError> {
+    let (a_len, n, d) = DATA_F32.with(|data| {
+        let data = data.borrow();
+        (
+            data.a.len() as u64,
+            data.b.len() as u64,
+            data.out.len() as u64,
+        )
+    });
+    check_lengths(a_len, n, d, 1)
+}
+
+/// Like `check_heap_shape`, but for the `i64` matrix.
+fn check_i64_shape() -> Result<(), MatMulError> {
+    let (a_len, n, d) = DATA_I64.with(|data| {
+        let data = data.borrow();
+        (
+            data.a.len() as u64,
+            data.b.len() as u64,
+            data.out.len() as u64,
+        )
+    });
+    check_lengths(a_len, n, d, 1)
+}
+
+fn check_lengths(a_len: u64, n: u64, d: u64, group: u64) -> Result<(), MatMulError> {
+    if a_len != n * d {
+        return fail(MatMulError::ShapeMismatch { a_len, n, d });
+    }
+    // Without any rows, nothing is read.
+    if d > 0 && !n.is_multiple_of(group) {
+        return fail(MatMulError::UnsupportedLength { n, group });
+    }
+    Ok(())
+}
+
+/// Checks that stable memory holds the whole of `layout`.
+fn check_stable_layout(layout: &Layout) -> Result<(), MatMulError> {
+    let needed = layout.size();
+    let allocated = stable_size() * WASM_PAGE_SIZE;
+    if needed > allocated {
+        return fail(MatMulError::StableMemoryTooSmall { needed, allocated });
+    }
+    Ok(())
+}
+
+/// Traps on `err` whatever the `ErrorMode`, for methods that run a multiply
+/// internally but don't return its result.
+fn trap_on_error(result: Result<(), MatMulError>) {
+    if let Err(err) = result {
+        trap(&err.to_string());
+    }
+}
+
+fn write_stable_i32s(offset: u64, vals: &[i32]) {
+    let bytes: Vec<u8> = vals.iter().flat_map(|val| val.to_le_bytes()).collect();
+    stable_write(offset, &bytes);
+}
+
+fn read_stable_i32s(offset: u64, len: usize) -> Vec<i32> {
+    let mut bytes = vec![0; len * 4];
+    stable_read(offset, &mut bytes);
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+fn v128_from_le_bytes(bytes: [u8; 16]) -> v128 {
+    let lane = |i: usize| i32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    i32x4(lane(0), lane(1), lane(2), lane(3))
+}
+
+/// Reads 16 bytes of stable memory at `addr` as a `v128`.
+///
+/// `ic0::stable_read_v128` requires `addr` to be 16-byte aligned, which the
+/// stable layout only guarantees when `n` is a multiple of 4 (rows of `A`
+/// start at multiples of `n * 4`, and `b` at `n * d * 4`). Unaligned reads fall
+/// back to a plain `stable_read`.
+fn read_v128(addr: u64) -> v128 {
+    if addr.is_multiple_of(16) {
+        unsafe { ic0::stable_read_v128(addr) }
+    } else {
+        let mut bytes = [0; 16];
+        stable_read(addr, &mut bytes);
+        v128_from_le_bytes(bytes)
+    }
+}
+
+fn read_elem<T: SimdLanes<LANES>, const LANES: usize>(addr: u64) -> i32 {
+    let mut bytes = [0; 4];
+    stable_read(addr, &mut bytes[..T::BYTES]);
+    T::from_le_bytes(&bytes[..T::BYTES])
+}
+
+/// Dot product of the `n` elements of type `T` at `a_addr` and `b_addr` in
+/// stable memory, `LANES` at a time.
+fn stable_dot<T: SimdLanes<LANES>, const LANES: usize>(a_addr: u64, b_addr: u64, n: u64) -> i32 {
+    let bytes = T::BYTES as u64;
+    stable_dot_with::<T, LANES>(
+        a_addr,
+        n,
+        |j| read_v128(b_addr + j * bytes),
+        |j| read_elem::<T, LANES>(b_addr + j * bytes),
+    )
+}
+
+/// Like `stable_dot`, but takes the `v128` group of `b` starting at element
+/// `j` from `b_group`, and the tail elements from `b_elem`.
+fn stable_dot_with<T: SimdLanes<LANES>, const LANES: usize>(
+    a_addr: u64,
+    n: u64,
+    b_group: impl Fn(u64) -> v128,
+    b_elem: impl Fn(u64) -> i32,
+) -> i32 {
+    let bytes = T::BYTES as u64;
+    // The last `n % LANES` elements don't fill a `v128`.
+    let simd_n = n - n % LANES as u64;
+
+    let mut vals = i32x4(0, 0, 0, 0);
+    for j in (0..simd_n).step_by(LANES) {
+        let a_group = read_v128(a_addr + j * bytes);
+        vals = T::mul_acc(vals, a_group, b_group(j));
+    }
+    // Wrapping, like the `v128` lanes, so the result matches `multiply_heap`
+    // even on overflow.
+    let mut val = i32x4_extract_lane::<0>(vals)
+        .wrapping_add(i32x4_extract_lane::<1>(vals))
+        .wrapping_add(i32x4_extract_lane::<2>(vals))
+        .wrapping_add(i32x4_extract_lane::<3>(vals));
+
+    for j in simd_n..n {
+        val = val.wrapping_add(read_elem::<T, LANES>(a_addr + j * bytes).wrapping_mul(b_elem(j)));
+    }
+    val
+}
+
+/// Dot product of the `n` `i32`s at `a_addr` and `b_addr` in stable memory,
+/// one element at a time.
+fn stable_dot_scalar(a_addr: u64, b_addr: u64, n: u64) -> i32 {
+    (0..n).fold(0_i32, |val, j| {
+        let product =
+            read_elem::<i32, 4>(a_addr + j * 4).wrapping_mul(read_elem::<i32, 4>(b_addr + j * 4));
+        val.wrapping_add(product)
+    })
+}
+
+/// Sets the `n` below which `multiply_stable` multiplies one element at a
+/// time, for shapes too small for the `v128` loop to pay off. The scalar loop
+/// reads `b` from stable memory even if `prepare_b_simd` has loaded it. The
+/// default of 0 always uses the `v128` loop.
+#[candid_method(update)]
+#[update]
+fn set_simd_threshold(threshold: usize) {
+    SIMD_THRESHOLD.with(|cell| cell.set(threshold));
+}
+
+fn uses_scalar_loop(n: u64) -> bool {
+    n < SIMD_THRESHOLD.with(Cell::get) as u64
+}
+
+/// Loads `b` from stable memory once, so that subsequent `multiply_stable`
+/// calls don't re-read it for every row. The cache is dropped whenever `b`
+/// changes.
+#[candid_method(update)]
+#[update]
+fn prepare_b_simd() {
+    let layout = Layout::current();
+    let (n, b_addr) = (layout.n, layout.b_addr());
+    let simd_n = n - n % 4;
+
+    let prepared = PreparedB {
+        groups: (0..simd_n)
+            .step_by(4)
+            .map(|j| read_v128(b_addr + j * 4))
+            .collect(),
+        tail: (simd_n..n)
+            .map(|j| read_elem::<i32, 4>(b_addr + j * 4))
+            .collect(),
+    };
+    PREPARED_B.with(|cached| *cached.borrow_mut() = Some(prepared));
+}
+
+#[candid_method(update)]
+#[update]
+pub fn multiply_stable() -> Result<(), MatMulError> {
+    let path = if PREPARED_B.with(|prepared| prepared.borrow().is_some()) {
+        "multiply_stable (prepared b)"
+    } else {
+        "multiply_stable"
+    };
+    logged(path, || {
+        let layout = Layout::current();
+        check_stable_layout(&layout)?;
+        count_multiply();
+        multiply_stable_into(layout.out_addr());
+        published_to_stable();
+        Ok(())
+    })
+}
+
+/// Describes how `multiply_stable` would run on the current shape, without
+/// running it. Each `v128` or tail element costs a stable read for `A`, and
+/// another for `b` unless `prepare_b_simd` has loaded it. Each row also reads
+/// its bias. Below the `set_simd_threshold` every element is in the tail.
+#[candid_method(query)]
+#[query]
+fn plan() -> Plan {
+    let layout = Layout::current();
+    let (n, d) = (layout.n, layout.d);
+    let scalar = uses_scalar_loop(n);
+    let simd_iterations = if scalar { 0 } else { d * (n / 4) };
+    let scalar_tail = if scalar { d * n } else { d * (n % 4) };
+    let reads_per_step = if !scalar && PREPARED_B.with(|prepared| prepared.borrow().is_some()) {
+        1
+    } else {
+        2
+    };
+    Plan {
+        element_bytes: 4,
+        host_reads: reads_per_step * (simd_iterations + scalar_tail) + d,
+        simd_iterations,
+        scalar_tail,
+    }
+}
+
+/// Like `multiply_stable`, but writes the output starting at `out_offset`
+/// rather than the layout's `out` region, for callers embedding this kernel in
+/// their own stable memory layout. The target range must be allocated, must
+/// not overlap any region of the layout, and must end before the saved
+/// matrices and run-length encoded `A` stored past it.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_to(out_offset: u64) -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    let len = layout.d * 4;
+    let end = out_offset.checked_add(len).unwrap_or_else(|| {
+        panic!(
+            "output range of {} bytes at {} overflows stable memory",
+            len, out_offset
+        )
+    });
+    let allocated = stable_size() * WASM_PAGE_SIZE;
+    assert!(
+        end <= allocated,
+        "output range [{}, {}) exceeds the {} allocated bytes",
+        out_offset,
+        end,
+        allocated
+    );
+    let target = Region::new("target", out_offset, len);
+    // Checked whatever the build, unlike `check_regions`, as the offset comes
+    // from the caller.
+    for region in layout.regions() {
+        assert!(
+            !target.overlaps(&region),
+            "output range [{}, {}) overlaps stable region {} [{}, {})",
+            target.start,
+            target.end(),
+            region.name,
+            region.start,
+            region.end()
+        );
+    }
+    if let Some(start) = named_start() {
+        assert!(
+            end <= start,
+            "output range [{}, {}) overlaps the saved matrices and runs starting at {}",
+            out_offset,
+            end,
+            start
+        );
+    }
+    count_multiply();
+    multiply_stable_into(out_offset);
+    Ok(())
+}
+
+fn multiply_stable_into(out_addr: u64) {
+    let layout = Layout::current();
+    let (n, d) = (layout.n, layout.d);
+
+    let a_addr = layout.a_addr();
+    let b_addr = layout.b_addr();
+    let bias_addr = layout.bias_addr();
+    let scalar = uses_scalar_loop(n);
+
+    PREPARED_B.with(|prepared| {
+        let prepared = prepared.borrow();
+        for i in 0..d {
+            let in_ = i * n * 4;
+            let val = match &*prepared {
+                _ if scalar => stable_dot_scalar(a_addr + in_, b_addr, n),
+                Some(b) => stable_dot_with::<i32, 4>(
+                    a_addr + in_,
+                    n,
+                    |j| b.groups[j as usize / 4],
+                    |j| b.tail[j as usize % 4],
+                ),
+                None => stable_dot::<i32, 4>(a_addr + in_, b_addr, n),
+            };
+            let val = val.wrapping_add(read_elem::<i32, 4>(bias_addr + i * 4));
+            unsafe { ic0::stable_write_i32(out_addr + i * 4, val) };
+        }
+    });
+}
+
+/// Like `multiply_heap_bias_relu`, but on stable memory and fused: outputs are
+/// computed four rows at a time, so the bias is added and the ReLU applied to
+/// a whole `v128` before anything is written back.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_bias_relu() -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    count_multiply();
+    let (n, d) = (layout.n, layout.d);
+    let bias_addr = layout.bias_addr();
+    let dot = |i: u64| stable_dot::<i32, 4>(layout.a_addr() + i * n * 4, layout.b_addr(), n);
+
+    // The last `d % 4` rows don't fill a `v128`.
+    let simd_d = d - d % 4;
+    let zero = i32x4(0, 0, 0, 0);
+    for i in (0..simd_d).step_by(4) {
+        let vals = i32x4(dot(i), dot(i + 1), dot(i + 2), dot(i + 3));
+        let vals = i32x4_max(i32x4_add(vals, read_v128(bias_addr + i * 4)), zero);
+        let out_addr = layout.out_addr() + i * 4;
+        unsafe {
+            ic0::stable_write_i32(out_addr, i32x4_extract_lane::<0>(vals));
+            ic0::stable_write_i32(out_addr + 4, i32x4_extract_lane::<1>(vals));
+            ic0::stable_write_i32(out_addr + 8, i32x4_extract_lane::<2>(vals));
+            ic0::stable_write_i32(out_addr + 12, i32x4_extract_lane::<3>(vals));
+        }
+    }
+    for i in simd_d..d {
+        let val = dot(i)
+            .wrapping_add(read_elem::<i32, 4>(bias_addr + i * 4))
+            .max(0);
+        unsafe { ic0::stable_write_i32(layout.out_addr() + i * 4, val) };
+    }
+    published_to_stable();
+    Ok(())
+}
+
+/// Dot product of the `n` little-endian `i32`s in each of `a` and `b`, loading
+/// `v128`s straight from the heap buffers. Wraps on overflow.
+fn buffer_dot(a: &[u8], b: &[u8], n: usize) -> i32 {
+    assert!(a.len() >= n * 4 && b.len() >= n * 4);
+    let simd_n = n - n % 4;
+    let mut vals = i32x4(0, 0, 0, 0);
+    for j in (0..simd_n).step_by(4) {
+        // Safe since `j * 4 + 16 <= n * 4`, within both buffers.
+        let (a_group, b_group) = unsafe {
+            (
+                v128_load(a.as_ptr().add(j * 4) as *const v128),
+                v128_load(b.as_ptr().add(j * 4) as *const v128),
+            )
+        };
+        vals = i32::mul_acc(vals, a_group, b_group);
+    }
+    let mut val = i32x4_extract_lane::<0>(vals)
+        .wrapping_add(i32x4_extract_lane::<1>(vals))
+        .wrapping_add(i32x4_extract_lane::<2>(vals))
+        .wrapping_add(i32x4_extract_lane::<3>(vals));
+    for j in simd_n..n {
+        let elem = |buf: &[u8]| i32::from_le_bytes(buf[j * 4..j * 4 + 4].try_into().unwrap());
+        val = val.wrapping_add(elem(a).wrapping_mul(elem(b)));
+    }
+    val
+}
+
+/// Like `multiply_stable`, but copies each row of `A` to the heap with a
+/// single `stable_read`, rather than a `stable_read_v128` per four elements,
+/// and reads `b` once up front. Trades `n / 4` small host calls per row for
+/// one large one.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_row_read() -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    count_multiply();
+    let (n, d) = (layout.n as usize, layout.d);
+
+    let mut b = vec![0; n * 4];
+    stable_read(layout.b_addr(), &mut b);
+    let mut row = vec![0; n * 4];
+    for i in 0..d {
+        stable_read(layout.a_addr() + i * n as u64 * 4, &mut row);
+        let val =
+            buffer_dot(&row, &b, n).wrapping_add(read_elem::<i32, 4>(layout.bias_addr() + i * 4));
+        unsafe { ic0::stable_write_i32(layout.out_addr() + i * 4, val) };
+    }
+    published_to_stable();
+    Ok(())
+}
+
+/// Like `multiply_stable`, but for a `d × n` matrix laid out in stable memory
+/// by the caller: everything, bias included, comes from stable memory, and
+/// the heap matrix is never read or written. The layout must end before any
+/// saved matrices or runs. With the current shape the output lands in the
+/// current `out` region and is what `get_output` returns from then on; with
+/// any other shape it is only in the caller's layout, and `get_output` keeps
+/// reading the output of the last other multiply.
+#[candid_method(update)]
+#[update]
+fn multiply_pure_stable(n: u64, d: u64) -> Result<(), MatMulError> {
+    let (Ok(rows), Ok(cols)) = (usize::try_from(n), usize::try_from(d)) else {
+        return fail(MatMulError::ShapeTooLarge { n, d });
+    };
+    if let Err(err) = check_shape(rows, cols) {
+        return fail(err);
+    }
+    let layout = Layout::new(n, d);
+    if let Some(start) = named_start() {
+        assert!(
+            layout.size() <= start,
+            "n = {}, d = {} would share stable memory with the named matrices or runs from byte {}",
+            n,
+            d,
+            start
+        );
+    }
+    let allocated = stable_size() * WASM_PAGE_SIZE;
+    // `check_shape` bounds the whole layout, so this can't overflow.
+    let bias_end = layout.bias_addr() + d * 4;
+    if bias_end > allocated {
+        return fail(MatMulError::StableMemoryTooSmall {
+            needed: bias_end,
+            allocated,
+        });
+    }
+    count_multiply();
+
+    for i in 0..d {
+        let in_ = i * n * 4;
+        let val = stable_dot::<i32, 4>(layout.a_addr() + in_, layout.b_addr(), n)
+            .wrapping_add(read_elem::<i32, 4>(layout.bias_addr() + i * 4));
+        unsafe { ic0::stable_write_i32(layout.out_addr() + i * 4, val) };
+    }
+    if layout == Layout::current() {
+        published_to_stable();
+    }
+    Ok(())
+}
+
+/// Like `multiply_stable`, but reads `b` from an interleaved layout: element
+/// `j` of `b` is the `i32` at `layout.size() + j * b_stride`, just past the
+/// standard layout. A stride of 4 is the contiguous case; any other stride
+/// gathers `b` one element at a time. The space past the layout is also where
+/// `save_matrix` keeps named matrices and `init_rle` its runs, which writing a
+/// strided `b` there would corrupt, so this refuses to run while any are
+/// stored.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_strided(b_stride: u64) -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    let (n, d) = (layout.n, layout.d);
+    if let Some(start) = named_start() {
+        panic!(
+            "the strided b would share stable memory with the named matrices or runs from byte {}",
+            start
+        );
+    }
+    assert!(
+        b_stride >= 4,
+        "b_stride = {} would overlap consecutive i32s",
+        b_stride
+    );
+    let b_addr = layout.size();
+    let allocated = stable_size() * WASM_PAGE_SIZE;
+    let b_end = if n == 0 {
+        Some(b_addr)
+    } else {
+        (n - 1)
+            .checked_mul(b_stride)
+            .and_then(|offset| offset.checked_add(b_addr + 4))
+    };
+    let b_end = b_end.unwrap_or_else(|| {
+        panic!(
+            "strided b ends past byte {}, but only {} are allocated",
+            u64::MAX,
+            allocated
+        )
+    });
+    assert!(
+        b_end <= allocated,
+        "strided b ends at byte {}, but only {} are allocated",
+        b_end,
+        allocated
+    );
+
+    count_multiply();
+    let b_elem = |j: u64| read_elem::<i32, 4>(b_addr + j * b_stride);
+    for i in 0..d {
+        let a_addr = layout.a_addr() + i * n * 4;
+        let val = if b_stride == 4 {
+            stable_dot::<i32, 4>(a_addr, b_addr, n)
+        } else {
+            stable_dot_with::<i32, 4>(
+                a_addr,
+                n,
+                |j| i32x4(b_elem(j), b_elem(j + 1), b_elem(j + 2), b_elem(j + 3)),
+                b_elem,
+            )
+        };
+        let val = val.wrapping_add(read_elem::<i32, 4>(layout.bias_addr() + i * 4));
+        unsafe { ic0::stable_write_i32(layout.out_addr() + i * 4, val) };
+    }
+    published_to_stable();
+    Ok(())
+}
+
+/// Dot product of the `n` `i32`s at `a_addr` and `b_addr` in stable memory,
+/// widening every product to `i64` so the result can't overflow.
+fn stable_dot_i64(a_addr: u64, b_addr: u64, n: u64) -> i64 {
+    let simd_n = n - n % 4;
+
+    let mut vals = i64x2(0, 0);
+    for j in (0..simd_n).step_by(4) {
+        let a_group = read_v128(a_addr + j * 4);
+        let b_group = read_v128(b_addr + j * 4);
+        vals = i64x2_add(vals, i64x2_extmul_low_i32x4(a_group, b_group));
+        vals = i64x2_add(vals, i64x2_extmul_high_i32x4(a_group, b_group));
+    }
+    let mut val = i64x2_extract_lane::<0>(vals) + i64x2_extract_lane::<1>(vals);
+
+    for j in simd_n..n {
+        val +=
+            read_elem::<i32, 4>(a_addr + j * 4) as i64 * read_elem::<i32, 4>(b_addr + j * 4) as i64;
+    }
+    val
+}
+
+/// Like `multiply_stable`, but accumulates in `i64` and writes each output as
+/// an 8-byte little-endian `i64` after the `i32` output. Read the result back
+/// with `get_output_i64`.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_i64() -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    count_multiply();
+    let (n, d) = (layout.n, layout.d);
+
+    for i in 0..d {
+        let in_ = i * n * 4;
+        let val = stable_dot_i64(layout.a_addr() + in_, layout.b_addr(), n)
+            + read_elem::<i32, 4>(layout.bias_addr() + i * 4) as i64;
+        stable_write(layout.out_i64_addr() + i * 8, &val.to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Like `multiply_stable`, but spread over as many calls as it takes to stay
+/// within `max_instructions` per message. Each call picks up at the row the
+/// last one stopped at and writes rows to the stable output until the next
+/// row, costed like the previous one, would take the instruction counter past
+/// `max_instructions`; it always computes at least one row. Returns the number
+/// of rows done so far: once it is `d`, the output is complete and the next
+/// call starts a new multiply. Reshaping starts over too.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_bounded(max_instructions: u64) -> u64 {
+    let layout = Layout::current();
+    trap_on_error(check_stable_layout(&layout));
+    let (n, d) = (layout.n, layout.d);
+    let mut row = BOUNDED_ROW.with(Cell::get);
+    if row == 0 {
+        count_multiply();
+    }
+    let mut row_cost = 0;
+    while row < d {
+        let start = instruction_counter();
+        if row_cost > 0 && start.saturating_add(row_cost) > max_instructions {
+            break;
+        }
+        let val = stable_dot::<i32, 4>(layout.a_addr() + row * n * 4, layout.b_addr(), n)
+            .wrapping_add(read_elem::<i32, 4>(layout.bias_addr() + row * 4));
+        unsafe { ic0::stable_write_i32(layout.out_addr() + row * 4, val) };
+        row_cost = instruction_counter() - start;
+        row += 1;
+    }
+    if row == d {
+        published_to_stable();
+    }
+    BOUNDED_ROW.with(|cell| cell.set(if row == d { 0 } else { row }));
+    row
+}
+
+/// Like `multiply_stable`, but accumulates in `i64` and clamps each output,
+/// bias included, to the `i32` range before writing it, rather than wrapping.
+/// Returns how many outputs were clamped.
+#[candid_method(update)]
+#[update]
+fn multiply_stable_saturating() -> u64 {
+    let layout = Layout::current();
+    trap_on_error(check_stable_layout(&layout));
+    count_multiply();
+    let (n, d) = (layout.n, layout.d);
+
+    let mut saturated = 0;
+    for i in 0..d {
+        let val = stable_dot_i64(layout.a_addr() + i * n * 4, layout.b_addr(), n)
+            + read_elem::<i32, 4>(layout.bias_addr() + i * 4) as i64;
+        let clamped = val.clamp(i32::MIN as i64, i32::MAX as i64);
+        if clamped != val {
+            saturated += 1;
+        }
+        unsafe { ic0::stable_write_i32(layout.out_addr() + i * 4, clamped as i32) };
+    }
+    published_to_stable();
+    saturated
+}
+
+#[candid_method(query)]
+#[query]
+fn get_output_i64() -> Vec<i64> {
+    let layout = Layout::current();
+    let mut bytes = vec![0; layout.d as usize * 8];
+    stable_read(layout.out_i64_addr(), &mut bytes);
+    bytes
+        .chunks_exact(8)
+        .map(|chunk| i64::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+#[candid_method(update)]
+#[update]
+pub fn multiply_stable_old() -> Result<(), MatMulError> {
+    let layout = Layout::current();
+    check_stable_layout(&layout)?;
+    // Reads `A` and `b` four elements at a time, with no tail.
+    check_heap_shape(4)?;
+    count_multiply();
+    let (n, d) = (layout.n, layout.d);
+
+    let a_addr = layout.a_addr();
+    let b_addr = layout.b_addr();
+    let out_addr = layout.out_addr();
+    let bias_addr = layout.bias_addr();
+    // let mut a_group = Box::new(i32x4(0, 0, 0, 0));
+    // let mut b_group = Box::new(i32x4(0, 0, 0, 0));
+    let mut a_group = [0_u8; 16];
+    let mut b_group = [0_u8; 16];
+
+    for i in 0..d {
+        let in_ = i * n * 4;
+        let mut val = 0_i32;
+        for j in (0..n).step_by(4) {
+            stable_read(a_addr + in_ + j * 4, &mut a_group);
+            stable_read(b_addr + j * 4, &mut b_group);
+            let a_group: [i32; 4] = unsafe { std::mem::transmute(a_group) };
+            let b_group: [i32; 4] = unsafe { std::mem::transmute(b_group) };
+            let a_group = a_group.as_ptr();
+            let b_group = b_group.as_ptr();
+
+            let mut ival: i32 = 0;
+            for i in 0..4 {
+                ival =
+                    ival.wrapping_add(unsafe { (*a_group.add(i)).wrapping_mul(*b_group.add(i)) });
+            }
+
+            val = val.wrapping_add(ival);
+        }
+        let val = val.wrapping_add(read_elem::<i32, 4>(bias_addr + i * 4));
+        stable_write(out_addr + i * 4, &val.to_le_bytes());
+    }
+    published_to_stable();
+    Ok(())
+}
+
+#[candid_method(update)]
+#[update]
+pub fn update_empty() {}
+
+/// Instructions executed so far in the current message.
+fn instruction_counter() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::performance_counter(0)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        stable::instruction_counter()
+    }
+}
+
+/// Turns on printing the shape, path and instruction count of every
+/// `multiply_heap` and `multiply_stable`, for debugging. Off by default.
+#[candid_method(update)]
+#[update]
+fn set_verbose(verbose: bool) {
+    VERBOSE.with(|cell| cell.set(verbose));
+}
+
+/// Runs the multiply `f`, printing `path`, the shape and the instructions it
+/// took if verbose logging is on. Otherwise it only costs checking the flag.
+fn logged<T>(path: &str, f: impl FnOnce() -> T) -> T {
+    if !VERBOSE.with(Cell::get) {
+        return f();
+    }
+    let start = instruction_counter();
+    let result = f();
+    let instructions = instruction_counter() - start;
+    let layout = Layout::current();
+    ic_cdk::println!(
+        "{}: n = {}, d = {}, {} instructions",
+        path,
+        layout.n,
+        layout.d,
+        instructions
+    );
+    #[cfg(test)]
+    LOG_LINES.with(|lines| lines.set(lines.get() + 1));
+    result
+}
+
+/// Runs `f` and returns the number of instructions it took.
+fn count_instructions(f: impl FnOnce()) -> u64 {
+    let start = instruction_counter();
+    f();
+    instruction_counter() - start
+}
+
+/// Runs `multiply_stable` `warmup` times untimed, then `iters` times timed,
+/// and returns the average instruction count of the timed runs. Warming up
+/// smooths out first-call effects such as page faults on stable memory.
+#[candid_method(update)]
+#[update]
+fn benchmark_n(warmup: u32, iters: u32) -> u64 {
+    assert!(iters > 0, "iters must be positive");
+    for _ in 0..warmup {
+        trap_on_error(multiply_stable());
+    }
+    let total = count_instructions(|| {
+        for _ in 0..iters {
+            trap_on_error(multiply_stable());
+        }
+    });
+    total / iters as u64
+}
+
+/// Measures `(multiply_stable_old, multiply_stable)` instruction counts on the
+/// current inputs, to quantify what the SIMD path saves.
+///
+/// `multiply_stable` is measured without any `b` loaded by `prepare_b_simd`,
+/// so that both read exactly the same stable memory.
+#[candid_method(update)]
+#[update]
+fn simd_speedup() -> (u64, u64) {
+    let prepared = PREPARED_B.with(|prepared| prepared.borrow_mut().take());
+    let scalar = count_instructions(|| trap_on_error(multiply_stable_old()));
+    let simd = count_instructions(|| trap_on_error(multiply_stable()));
+    PREPARED_B.with(|cached| *cached.borrow_mut() = prepared);
+    (scalar, simd)
+}
+
+/// The instructions taken to read all of `A` from stable memory, a `v128` at a
+/// time as `multiply_stable` does, without any arithmetic. Comparing against
+/// `multiply_stable` splits its cost into I/O and arithmetic.
+#[candid_method(update)]
+#[update]
+fn bench_stable_reads() -> u64 {
+    measure_read_bandwidth().1
+}
+
+/// Reads all of `A` like `bench_stable_reads`, and returns the bytes read
+/// along with the instructions it took, for an effective bytes-per-instruction
+/// figure of stable I/O alone.
+#[candid_method(update)]
+#[update]
+fn measure_read_bandwidth() -> (u64, u64) {
+    let layout = Layout::current();
+    let len = layout.n * layout.d * 4;
+    let simd_len = len - len % 16;
+    let instructions = count_instructions(|| {
+        // `black_box` keeps the unused reads from being optimized away.
+        for addr in (layout.a_addr()..layout.a_addr() + simd_len).step_by(16) {
+            std::hint::black_box(read_v128(addr));
+        }
+        if simd_len < len {
+            let mut tail = [0; 16];
+            stable_read(
+                layout.a_addr() + simd_len,
+                &mut tail[..(len - simd_len) as usize],
+            );
+            std::hint::black_box(tail);
+        }
+    });
+    (len, instructions)
+}
+
+/// Measures `(multiply_stable, multiply_stable_row_read)` instruction counts on
+/// the current inputs, to compare per-`v128` reads against whole-row reads.
+/// Like `simd_speedup`, `multiply_stable` runs without a prepared `b`.
+#[candid_method(update)]
+#[update]
+fn row_read_speedup() -> (u64, u64) {
+    let prepared = PREPARED_B.with(|prepared| prepared.borrow_mut().take());
+    let per_v128 = count_instructions(|| trap_on_error(multiply_stable()));
+    let row_read = count_instructions(|| trap_on_error(multiply_stable_row_read()));
+    PREPARED_B.with(|cached| *cached.borrow_mut() = prepared);
+    (per_v128, row_read)
+}
+
+/// The sum of squares of each of the `d` rows of `A`, for normalizing rows
+/// before multiplying. Sums beyond `u64::MAX` saturate.
+#[candid_method(query)]
+#[query]
+fn a_row_norms_sq() -> Vec<u64> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let n = data.b.len();
+        (0..data.out.len())
+            .map(|i| {
+                data.a[i * n..(i + 1) * n]
+                    .iter()
+                    .map(|a| a.unsigned_abs() as u64)
+                    .fold(0_u64, |acc, a| acc.saturating_add(a * a))
+            })
+            .collect()
+    })
+}
+
+/// The shape, element type and layout of the `i32` matrix in one call.
+#[candid_method(query)]
+#[query]
+fn describe() -> Description {
+    let layout = Layout::current();
+    Description {
+        n: layout.n,
+        d: layout.d,
+        dtype: DType::I32,
+        // Setting a shape always allocates stable memory.
+        initialized: stable_size() > 0,
+        storage_order: StorageOrder::RowMajor,
+    }
+}
+
+/// Selects which of the `i32`, `f32` and `i64` matrices `multiply_active`
+/// multiplies. The three are held side by side, so switching between them
+/// doesn't reload anything.
+#[candid_method(update)]
+#[update]
+fn set_active_dtype(dtype: DType) {
+    ACTIVE_DTYPE.with(|cell| cell.set(dtype));
+}
+
+/// The matrix selected by `set_active_dtype`, `I32` by default.
+#[candid_method(query)]
+#[query]
+fn active_dtype() -> DType {
+    ACTIVE_DTYPE.with(Cell::get)
+}
+
+/// Multiplies the active matrix on the heap: `multiply_heap`,
+/// `multiply_heap_f32` or `multiply_heap_i64`. Read the output with the
+/// matching getter.
+#[candid_method(update)]
+#[update]
+fn multiply_active() -> Result<(), MatMulError> {
+    match active_dtype() {
+        DType::I32 => multiply_heap()?,
+        DType::F32 => multiply_heap_f32()?,
+        DType::I64 => multiply_heap_i64()?,
+    }
+    Ok(())
+}
+
+/// Each region of the stable layout as `(name, offset, misalignment)`, where
+/// the misalignment is the offset modulo 16: 0 for a region that
+/// `stable_read_v128` can read directly, and otherwise the bytes past the last
+/// 16-byte boundary. For clients writing to stable memory themselves, and to
+/// spot shapes whose `v128` reads fall back to `stable_read`.
+#[candid_method(query)]
+#[query]
+fn layout_alignment() -> Vec<(String, u64, u64)> {
+    Layout::current()
+        .regions()
+        .iter()
+        .map(|region| (region.name.to_string(), region.start, region.start % 16))
+        .collect()
+}
+
+#[candid_method(query)]
+#[query]
+fn memory_stats() -> MemoryStats {
+    MemoryStats {
+        stable_allocated_bytes: stable_size() * WASM_PAGE_SIZE,
+        stable_used_bytes: NAMED.with(|named| {
+            let rle = RLE_A.with(|rle| rle.borrow().as_ref().map(|rle| rle.addr + rle.runs * 12));
+            named
+                .borrow()
+                .values()
+                .map(|named| named.addr + named.len)
+                .chain(rle)
+                .fold(Layout::current().size(), u64::max)
+        }),
+    }
+}
+
+/// The most `i32` elements, across all regions, that fit in the maximum
+/// stable memory alongside the metadata. A shape needs `n * d + n + d`
+/// elements plus `4 * d` for the bias, the `i64` output and the snapshot.
+#[candid_method(query)]
+#[query]
+fn max_shape() -> u64 {
+    (MAX_STABLE_BYTES - META_LEN) / 4
+}
+
+/// The arithmetic operations in one `A·b` of the current shape: `n * d`
+/// multiplies and as many adds. Dividing `benchmark_n` by this gives the
+/// instructions spent per operation.
+#[candid_method(query)]
+#[query]
+fn op_count() -> u64 {
+    let layout = Layout::current();
+    2 * layout.n * layout.d
+}
+
+/// Which multiply suits the current shape and inputs: `"heap"`, `"stable"`
+/// or `"stable_i16"`.
+///
+/// An `A` of up to `HEAP_PATH_MAX_BYTES` (1 GiB) is best multiplied on the
+/// heap, which needs no stable reads and leaves room in the 4 GiB wasm heap
+/// for the rest of the state. Beyond that, the stable path is preferred; and
+/// if every element of `A` and `b` fits an `i16`, storing them as `i16`s
+/// halves the bytes each `stable_dot` reads.
+#[candid_method(query)]
+#[query]
+fn recommended_path() -> String {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let fits_i16 = || {
+            data.a
+                .iter()
+                .chain(&data.b)
+                .all(|&val| i16::try_from(val).is_ok())
+        };
+        recommend_path(data.b.len() as u64, data.out.len() as u64, fits_i16).to_string()
+    })
+}
+
+/// The heuristic behind `recommended_path`. `fits_i16` is only called for
+/// shapes too large for the heap.
+fn recommend_path(n: u64, d: u64, fits_i16: impl FnOnce() -> bool) -> &'static str {
+    if n.saturating_mul(d).saturating_mul(4) <= HEAP_PATH_MAX_BYTES {
+        "heap"
+    } else if fits_i16() {
+        "stable_i16"
+    } else {
+        "stable"
+    }
+}
+
+pub fn matmul<const GROUP_SIZE: usize>() {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+
+        let n = data.b.len();
+        let d = data.out.len();
+
+        assert_eq!(data.a.len(), n * d);
+
+        // The last `n % GROUP_SIZE` elements don't fill a group.
+        let group_n = n - n % GROUP_SIZE;
+
+        for i in 0..d {
+            let in_ = i * n;
+            let mut val: i32 = 0;
+
+            // matmul in groups of `GROUP_SIZE`.
+            for j in (0..group_n).step_by(GROUP_SIZE) {
+                // NOTE: Using raw pointer arithmetic as it reduces the overhead of bound checks.
+                // Expeirments showed that it reduces instructions by 6.6%.
+                unsafe {
+                    let b_group = data.b.as_ptr().add(j);
+                    let a_group = data.a.as_ptr().add(in_ + j);
+
+                    // multiply and sum both groups.
+                    let mut ival: i32 = 0;
+                    for i in 0..GROUP_SIZE {
+                        ival = ival.wrapping_add((*a_group.add(i)).wrapping_mul(*b_group.add(i)));
+                    }
+
+                    val = val.wrapping_add(ival);
+                }
+            }
+            for j in group_n..n {
+                val = val.wrapping_add(data.a[in_ + j].wrapping_mul(data.b[j]));
+            }
+            let val = val.wrapping_add(data.bias[i]);
+            unsafe { *data.out_back.as_mut_ptr().add(i) = val };
+        }
+        data.publish_output();
+    });
+}
+
+#[candid_method(update)]
+#[update]
+fn multiply_heap() -> Result<(), MatMulError> {
+    logged("multiply_heap", || {
+        check_heap_shape(1)?;
+        count_multiply();
+        matmul::<64>();
+        Ok(())
+    })
+}
+
+/// Like `multiply_heap`, but also counts the products with both factors
+/// nonzero, to show how much of the work was spent multiplying by zero.
+/// Returns the output and that count.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_nnz() -> (Vec<i32>, u64) {
+    trap_on_error(check_heap_shape(1));
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        let mut nnz = 0;
+        for (i, out) in data.out_back.iter_mut().enumerate() {
+            let mut val = data.bias[i];
+            for (&a, &b) in data.a[i * n..(i + 1) * n].iter().zip(&data.b) {
+                if a != 0 && b != 0 {
+                    nnz += 1;
+                    val = val.wrapping_add(a.wrapping_mul(b));
+                }
+            }
+            *out = val;
+        }
+        data.publish_output();
+        (data.out.clone(), nnz)
+    })
+}
+
+/// Like `multiply_heap`, but also finds the index of the largest output as
+/// it goes, taking the first on ties, rather than in a second pass over the
+/// output. Returns the output and that index, or `None` if `d = 0`.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_with_argmax() -> (Vec<i32>, Option<u64>) {
+    trap_on_error(check_heap_shape(1));
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        let (mut best, mut max) = (0, i32::MIN);
+        for (i, out) in data.out_back.iter_mut().enumerate() {
+            *out = data.a[i * n..(i + 1) * n]
+                .iter()
+                .zip(&data.b)
+                .fold(data.bias[i], |acc, (&a, &b)| {
+                    acc.wrapping_add(a.wrapping_mul(b))
+                });
+            if i == 0 || *out > max {
+                (best, max) = (i, *out);
+            }
+        }
+        data.publish_output();
+        let best = (!data.out.is_empty()).then_some(best as u64);
+        (data.out.clone(), best)
+    })
+}
+
+/// Computes the next `batch` outputs of `multiply_heap`, bias included, and
+/// returns just those, so that a large `d` can be walked through over several
+/// calls with progress along the way. Each call continues where the last left
+/// off; after the last row, the next call starts over from row 0, as it does
+/// once `A` or `b` changes. The stored output is left alone.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_progressive(batch: u64) -> Vec<i32> {
+    assert!(batch > 0, "batch must be positive");
+    let start = PROGRESSIVE_ROW.with(Cell::get);
+    if start == 0 {
+        count_multiply();
+    }
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len() as u64);
+        let end = start.saturating_add(batch).min(d);
+        PROGRESSIVE_ROW.with(|row| row.set(if end == d { 0 } else { end }));
+        (start as usize..end as usize)
+            .map(|i| {
+                data.a[i * n..(i + 1) * n]
+                    .iter()
+                    .zip(&data.b)
+                    .fold(data.bias[i], |acc, (&a, &b)| {
+                        acc.wrapping_add(a.wrapping_mul(b))
+                    })
+            })
+            .collect()
+    })
+}
+
+/// Runs `multiply_heap`, then average-pools the output: returns the `d / pool`
+/// means of consecutive groups of `pool` outputs, each summed in an `i64` and
+/// divided with truncation towards zero. `pool` must divide `d`. The full
+/// output stays available through `get_output`.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_pooled(pool: u64) -> Vec<i32> {
+    let d = DATA.with(|data| data.borrow().out.len() as u64);
+    assert!(
+        pool > 0 && d.is_multiple_of(pool),
+        "pool = {} must divide d = {}",
+        pool,
+        d
+    );
+    trap_on_error(multiply_heap());
+    DATA.with(|data| {
+        data.borrow()
+            .out
+            .chunks(pool as usize)
+            .map(|group| (group.iter().map(|&val| val as i64).sum::<i64>() / pool as i64) as i32)
+            .collect()
+    })
+}
+
+/// Asks the running, or else the next, `multiply_heap_cancellable` to stop at
+/// its next row.
+#[candid_method(update)]
+#[update]
+fn cancel() {
+    CANCELLED.with(|cancelled| cancelled.set(true));
+}
+
+/// Like `multiply_heap`, but checks for a `cancel` between output rows. If
+/// cancelled, it stops there and the previous output stays published;
+/// otherwise the new output is published as usual. Either way, returns the
+/// number of rows computed, which is `d` unless cancelled.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_cancellable() -> u64 {
+    trap_on_error(check_heap_shape(1));
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        for (i, out) in data.out_back.iter_mut().enumerate() {
+            #[cfg(test)]
+            if CANCEL_AT_ROW.with(Cell::get) == Some(i as u64) {
+                cancel();
+            }
+            if CANCELLED.with(|cancelled| cancelled.replace(false)) {
+                return i as u64;
+            }
+            *out = data.a[i * n..(i + 1) * n]
+                .iter()
+                .zip(&data.b)
+                .fold(data.bias[i], |acc, (&a, &b)| {
+                    acc.wrapping_add(a.wrapping_mul(b))
+                });
+        }
+        data.publish_output();
+        data.out.len() as u64
+    })
+}
+
+/// Packs the current `A`, every element of which must be -1, 0 or 1, into
+/// two bits per weight for `multiply_ternary`. The packed copy is a snapshot:
+/// later changes to `A` need packing again.
+#[candid_method(update)]
+#[update]
+fn pack_ternary_a() {
+    let packed = DATA.with(|data| {
+        let data = data.borrow();
+        let mut packed = vec![0_u8; data.a.len().div_ceil(4)];
+        for (k, &weight) in data.a.iter().enumerate() {
+            let bits = match weight {
+                0 => 0b00,
+                1 => 0b01,
+                -1 => 0b11,
+                _ => panic!("A[{}] = {} is not ternary", k, weight),
+            };
+            packed[k / 4] |= bits << (2 * (k % 4));
+        }
+        TernaryA {
+            packed,
+            n: data.b.len(),
+            d: data.out.len(),
+        }
+    });
+    TERNARY_A.with(|ternary| *ternary.borrow_mut() = packed);
+}
+
+/// Like `multiply_heap` with the weights packed by `pack_ternary_a`, but
+/// without multiplying: each weight adds `b[j]`, subtracts it, or skips it.
+/// The packed `A` must have the current shape.
+#[candid_method(update)]
+#[update]
+fn multiply_ternary() -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    count_multiply();
+    TERNARY_A.with(|ternary| {
+        let ternary = ternary.borrow();
+        DATA.with(|data| {
+            let mut data = data.borrow_mut();
+            let data = &mut *data;
+            let (n, d) = (data.b.len(), data.out.len());
+            assert_eq!(
+                (ternary.n, ternary.d),
+                (n, d),
+                "the packed A has n = {}, d = {}, but the matrix has n = {}, d = {}",
+                ternary.n,
+                ternary.d,
+                n,
+                d
+            );
+            for (i, out) in data.out_back.iter_mut().enumerate() {
+                let mut val = data.bias[i];
+                for (j, &b) in data.b.iter().enumerate() {
+                    let k = i * n + j;
+                    match ternary.packed[k / 4] >> (2 * (k % 4)) & 0b11 {
+                        0b01 => val = val.wrapping_add(b),
+                        0b11 => val = val.wrapping_sub(b),
+                        _ => {}
+                    }
+                }
+                *out = val;
+            }
+            data.publish_output();
+        });
+    });
+    Ok(())
+}
+
+/// Stores `A` for the current shape as `(value, count)` runs in a stable
+/// region of its own past the layout, for `multiply_rle`. The runs cover `A`
+/// in row-major order and may cross rows; their counts must add up to
+/// `n * d`. Storing replaces any earlier runs, and leaves the dense `A` as it
+/// was.
+#[candid_method(update)]
+#[update]
+fn init_rle(runs: Vec<(i32, u64)>) {
+    let layout = Layout::current();
+    let total = runs
+        .iter()
+        .try_fold(0_u64, |total, &(_, count)| total.checked_add(count));
+    assert!(
+        total == Some(layout.n * layout.d),
+        "the runs must cover n * d = {} elements, got {:?}",
+        layout.n * layout.d,
+        total
+    );
+    if let Some(old) = RLE_A.with(|rle| rle.borrow_mut().take()) {
+        NAMED_ALLOC.with(|alloc| alloc.borrow_mut().free(old.addr, old.runs * 12));
+    }
+    let bytes: Vec<u8> = runs
+        .iter()
+        .flat_map(|&(value, count)| value.to_le_bytes().into_iter().chain(count.to_le_bytes()))
+        .collect();
+    let len = bytes.len() as u64;
+    let addr = NAMED_ALLOC.with(|alloc| alloc.borrow_mut().alloc(len, layout.size()));
+    let pages = (addr + len).div_ceil(WASM_PAGE_SIZE);
+    if pages > stable_size() {
+        stable_grow(pages - stable_size()).unwrap();
+    }
+    stable_write(addr, &bytes);
+    RLE_A.with(|rle| {
+        *rle.borrow_mut() = Some(RleA {
+            addr,
+            runs: runs.len() as u64,
+            n: layout.n as usize,
+            d: layout.d as usize,
+        })
+    });
+}
+
+/// Like `multiply_heap` with the `A` stored by `init_rle`, which is never
+/// expanded: the part of a run within one row adds its value times the sum of
+/// the `b` elements it covers, taken from prefix sums of `b`. The runs must
+/// have been stored for the current shape.
+#[candid_method(update)]
+#[update]
+fn multiply_rle() -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    count_multiply();
+    let Some((addr, runs, rle_n, rle_d)) = RLE_A.with(|rle| {
+        rle.borrow()
+            .as_ref()
+            .map(|rle| (rle.addr, rle.runs, rle.n, rle.d))
+    }) else {
+        panic!("no run-length encoded A; call init_rle first");
+    };
+    let mut bytes = vec![0; runs as usize * 12];
+    stable_read(addr, &mut bytes);
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let (n, d) = (data.b.len(), data.out.len());
+        assert_eq!(
+            (rle_n, rle_d),
+            (n, d),
+            "the runs are for n = {}, d = {}, but the matrix has n = {}, d = {}",
+            rle_n,
+            rle_d,
+            n,
+            d
+        );
+        // `prefix[j]` is the wrapping sum of `b[..j]`, which is all that's
+        // needed as the products wrap too.
+        let mut prefix = vec![0_i32; n + 1];
+        for (j, &b) in data.b.iter().enumerate() {
+            prefix[j + 1] = prefix[j].wrapping_add(b);
+        }
+        data.out_back.copy_from_slice(&data.bias);
+        let mut k = 0;
+        for run in bytes.chunks_exact(12) {
+            let value = i32::from_le_bytes(run[..4].try_into().unwrap());
+            let mut count = u64::from_le_bytes(run[4..].try_into().unwrap()) as usize;
+            while count > 0 {
+                let (i, j) = (k / n, k % n);
+                let len = count.min(n - j);
+                let sum = prefix[j + len].wrapping_sub(prefix[j]);
+                data.out_back[i] = data.out_back[i].wrapping_add(value.wrapping_mul(sum));
+                k += len;
+                count -= len;
+            }
+        }
+        data.publish_output();
+    });
+    Ok(())
+}
+
+/// Runs `multiply_heap` and returns just the sign of each output, `-1`, `0` or
+/// `1`: a quarter of the bytes of the full output, and all that a sign-based
+/// decision needs.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_signs() -> Vec<i8> {
+    trap_on_error(multiply_heap());
+    DATA.with(|data| {
+        data.borrow()
+            .out
+            .iter()
+            .map(|val| val.signum() as i8)
+            .collect()
+    })
+}
+
+/// Runs `multiply_heap` and quantizes the output symmetrically to `i8`, to
+/// feed into another int8 layer. The scale maps the output's largest
+/// magnitude to 127, so `q[i] as f32 * scale` is within `scale / 2` of
+/// `out[i]`. Returns the quantized output and the scale, which is 1 for an
+/// all-zero output.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_quantize_out() -> (Vec<i8>, f32) {
+    trap_on_error(multiply_heap());
+    DATA.with(|data| {
+        let out = &data.borrow().out;
+        let max_abs = out.iter().map(|&val| (val as i64).abs()).max().unwrap_or(0);
+        let scale = if max_abs == 0 {
+            1.0
+        } else {
+            max_abs as f32 / i8::MAX as f32
+        };
+        let quantized = out
+            .iter()
+            .map(|&val| (val as f32 / scale).round().clamp(-127.0, 127.0) as i8)
+            .collect();
+        (quantized, scale)
+    })
+}
+
+/// Like `multiply_heap` with a binary `b` given as `bits`, where bit `j % 8`
+/// of `bits[j / 8]` is `b[j]`: each output is the bias plus the sum of the
+/// elements of its row whose bit is set. The stored `b` is ignored.
+#[candid_method(update)]
+#[update]
+fn multiply_binary_b(bits: Vec<u8>) -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        assert_eq!(
+            bits.len(),
+            n.div_ceil(8),
+            "bits must have length {} for n = {}, got {}",
+            n.div_ceil(8),
+            n,
+            bits.len()
+        );
+        assert!(
+            n.is_multiple_of(8) || bits.last().unwrap() >> (n % 8) == 0,
+            "bits beyond n = {} are set",
+            n
+        );
+        for (i, out) in data.out_back.iter_mut().enumerate() {
+            let row = &data.a[i * n..(i + 1) * n];
+            let mut val = data.bias[i];
+            for (byte_index, &byte) in bits.iter().enumerate() {
+                // Visits only the set bits, lowest first.
+                let mut byte = byte;
+                while byte != 0 {
+                    let j = byte_index * 8 + byte.trailing_zeros() as usize;
+                    val = val.wrapping_add(row[j]);
+                    byte &= byte - 1;
+                }
+            }
+            *out = val;
+        }
+        data.publish_output();
+    });
+    Ok(())
+}
+
+/// Runs `multiply_heap`, which adds the bias, then clamps the output at zero
+/// in a separate pass.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_bias_relu() -> Result<(), MatMulError> {
+    multiply_heap()?;
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        for out in data.out.iter_mut() {
+            *out = (*out).max(0);
+        }
+    });
+    Ok(())
+}
+
+/// Runs `multiply_heap` and returns whether the output is exactly
+/// `expected`, so that a deployment can be checked against golden outputs in
+/// one call.
+#[candid_method(update)]
+#[update]
+fn multiply_and_check(expected: Vec<i32>) -> bool {
+    let d = DATA.with(|data| data.borrow().out.len());
+    assert_eq!(
+        expected.len(),
+        d,
+        "expected must have length {}, got {}",
+        d,
+        expected.len()
+    );
+    trap_on_error(multiply_heap());
+    DATA.with(|data| data.borrow().out == expected)
+}
+
+/// The dot product of row `row` of `A` with `b`, without the bias and without
+/// touching the output. Like the multiplies, it wraps on overflow.
+#[candid_method(query)]
+#[query]
+fn dot(row: u32) -> i32 {
+    dot_rows(vec![row])[0]
+}
+
+/// Like `dot`, for each of `rows` in order, in one call. Every index must be
+/// below `d`.
+#[candid_method(query)]
+#[query]
+fn dot_rows(rows: Vec<u32>) -> Vec<i32> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        rows.iter()
+            .map(|&row| {
+                assert!(
+                    (row as usize) < d,
+                    "row {} is out of range for d = {}",
+                    row,
+                    d
+                );
+                let start = row as usize * n;
+                data.a[start..start + n]
+                    .iter()
+                    .zip(&data.b)
+                    .fold(0_i32, |acc, (a, b)| acc.wrapping_add(a.wrapping_mul(*b)))
+            })
+            .collect()
+    })
+}
+
+/// The Frobenius inner product of `A` with `other`, given in the same row-major
+/// `d × n` shape: the sum of their element-wise products, accumulated in an
+/// `i64` that wraps on overflow.
+#[candid_method(query)]
+#[query]
+fn frobenius_dot(other: Vec<i32>) -> i64 {
+    DATA.with(|data| {
+        let a = &data.borrow().a;
+        assert_eq!(
+            other.len(),
+            a.len(),
+            "other must have length {}, got {}",
+            a.len(),
+            other.len()
+        );
+        a.iter()
+            .zip(&other)
+            .fold(0_i64, |acc, (&a, &b)| acc.wrapping_add(a as i64 * b as i64))
+    })
+}
+
+/// Dots each row of `A` with its own vector: `vectors` holds `d` vectors of
+/// length `n`, and output `i` is row `i` of `A` dotted with `vectors[i]`, plus
+/// the bias of row `i`. Neither `b` nor the stored output are involved.
+#[candid_method(update)]
+#[update]
+fn multiply_rowwise(vectors: Vec<Vec<i32>>) -> Vec<i32> {
+    count_multiply();
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        assert_eq!(
+            vectors.len(),
+            d,
+            "vectors must have length {}, got {}",
+            d,
+            vectors.len()
+        );
+        vectors
+            .iter()
+            .enumerate()
+            .map(|(i, vector)| {
+                assert_eq!(
+                    vector.len(),
+                    n,
+                    "vector {} must have length {}, got {}",
+                    i,
+                    n,
+                    vector.len()
+                );
+                data.a[i * n..(i + 1) * n]
+                    .iter()
+                    .zip(vector)
+                    .fold(data.bias[i], |acc, (&a, &v)| {
+                        acc.wrapping_add(a.wrapping_mul(v))
+                    })
+            })
+            .collect()
+    })
+}
+
+/// Replaces `x`, which must have length `d`.
+#[candid_method(update)]
+#[update]
+fn set_x(x: Vec<i32>) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let d = data.out.len();
+        assert_eq!(x.len(), d, "x must have length {}, got {}", d, x.len());
+        data.x = x;
+    });
+}
+
+/// Computes `Aᵀ·x`, a length-`n` vector with `out_transpose[j]` the sum of
+/// `A[i][j] * x[i]`. Reading `A` a column at a time would stride across rows,
+/// so instead each row, scaled by `x[i]`, is added to the whole output.
+#[candid_method(update)]
+#[update]
+fn multiply_transpose() -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        data.out_transpose.fill(0);
+        for (row, &x) in data.a.chunks_exact(n.max(1)).zip(&data.x) {
+            for (out, &a) in data.out_transpose.iter_mut().zip(row) {
+                *out = out.wrapping_add(a.wrapping_mul(x));
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Returns `(A·b, Aᵀ·x)` from a single pass over `A`, for methods that need
+/// both products. Like `multiply_heap`, `A·b` includes the bias; `Aᵀ·x`
+/// doesn't. The stored outputs are unchanged.
+#[candid_method(update)]
+#[update]
+fn multiply_both(x: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+    count_multiply();
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        assert_eq!(x.len(), d, "x must have length {}, got {}", d, x.len());
+        let mut forward = data.bias.clone();
+        let mut transpose = vec![0_i32; n];
+        for ((row, &x), forward) in data.a.chunks_exact(n.max(1)).zip(&x).zip(&mut forward) {
+            for ((&a, &b), transpose) in row.iter().zip(&data.b).zip(&mut transpose) {
+                *forward = a.wrapping_mul(b).wrapping_add(*forward);
+                *transpose = a.wrapping_mul(x).wrapping_add(*transpose);
+            }
+        }
+        (forward, transpose)
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn get_output_transpose() -> Vec<i32> {
+    DATA.with(|data| data.borrow().out_transpose.clone())
+}
+
+/// `A·b` for the one-hot `b` with a single 1 at index `k`, which is just
+/// column `k` of `A` plus the bias, read without multiplying. The stored `b`
+/// is ignored.
+#[candid_method(query)]
+#[query]
+fn multiply_onehot(k: u64) -> Vec<i32> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let n = data.b.len();
+        assert!((k as usize) < n, "k = {} is out of range for n = {}", k, n);
+        data.a
+            .iter()
+            .skip(k as usize)
+            .step_by(n)
+            .zip(&data.bias)
+            .map(|(&a, &bias)| a.wrapping_add(bias))
+            .collect()
+    })
+}
+
+/// Runs `multiply_heap` and returns only the nonzero outputs, as
+/// `(index, value)` pairs in index order, for outputs that are mostly zero.
+#[candid_method(update)]
+#[update]
+fn multiply_sparse_output() -> Vec<(u64, i32)> {
+    trap_on_error(multiply_heap());
+    DATA.with(|data| {
+        data.borrow()
+            .out
+            .iter()
+            .enumerate()
+            .filter(|(_, &val)| val != 0)
+            .map(|(i, &val)| (i as u64, val))
+            .collect()
+    })
+}
+
+/// Runs `multiply_heap` and returns the index of the largest output. Ties go
+/// to the last such index if `prefer_last`, and to the first otherwise. With
+/// `d = 0` there is no largest output, and it returns `None`.
+#[candid_method(update)]
+#[update]
+fn multiply_argmax_dir(prefer_last: bool) -> Option<u64> {
+    trap_on_error(multiply_heap());
+    DATA.with(|data| {
+        let data = data.borrow();
+        if data.out.is_empty() {
+            return None;
+        }
+        let mut best = 0;
+        for (i, &val) in data.out.iter().enumerate().skip(1) {
+            let max = data.out[best];
+            if val > max || (prefer_last && val == max) {
+                best = i;
+            }
+        }
+        Some(best as u64)
+    })
+}
+
+/// Runs `iters` steps of power iteration on the square `A`, starting from
+/// `b`: each step computes `v = A·v` and rescales it so that its largest
+/// magnitude is `POWER_ITERATE_SCALE` (2^15), keeping the next step's sums
+/// small. The bias doesn't apply. Stores the final `v` as the output and
+/// returns it; it converges along `A`'s dominant eigenvector.
+#[candid_method(update)]
+#[update]
+fn power_iterate(iters: u32) -> Vec<i32> {
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let (n, d) = (data.b.len(), data.out.len());
+        assert_eq!(n, d, "power iteration needs a square A, got {} × {}", d, n);
+        let mut v = data.b.clone();
+        for _ in 0..iters {
+            let next: Vec<i128> = data
+                .a
+                .chunks(n)
+                .map(|row| {
+                    row.iter()
+                        .zip(&v)
+                        .map(|(&a, &v)| a as i128 * v as i128)
+                        .sum()
+                })
+                .collect();
+            let max_abs = next.iter().map(|val| val.abs()).max().unwrap_or(0);
+            if max_abs == 0 {
+                v = vec![0; n];
+                break;
+            }
+            v = next
+                .iter()
+                .map(|val| (val * POWER_ITERATE_SCALE / max_abs) as i32)
+                .collect();
+        }
+        data.out_back.copy_from_slice(&v);
+        data.publish_output();
+        v
+    })
+}
+
+/// Largest magnitude of `power_iterate`'s vector after each step.
+const POWER_ITERATE_SCALE: i128 = 1 << 15;
+
+/// Computes `A·b` plus the bias, accumulating each output in an `i128`, and
+/// stores `store(acc, prev)` for every row, where `prev` is the row's previous
+/// output. Even an `i64` overflows on two `i32::MIN * i32::MIN` products, but
+/// no shape that fits in stable memory can overflow the `i128`. Variants that
+/// post-process the dot product go through here so the post-processing can
+/// start from the exact sum rather than one that overflowed `i32`, although
+/// `multiply_heap_ema` deliberately wraps it first.
+fn matmul_wide(store: impl Fn(i128, i32) -> i32) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+
+        let n = data.b.len();
+        let d = data.out.len();
+
+        assert_eq!(data.a.len(), n * d);
+
+        for i in 0..d {
+            let row = &data.a[i * n..(i + 1) * n];
+            let acc = row
+                .iter()
+                .zip(&data.b)
+                .fold(data.bias[i] as i128, |acc, (&a, &b)| {
+                    acc + a as i128 * b as i128
+                });
+            data.out_back[i] = store(acc, data.out[i]);
+        }
+        data.publish_output();
+    });
+}
+
+/// Multiplies with each output, bias included, accumulated exactly and stored
+/// according to `mode`, and returns the output.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_mode(mode: OverflowMode) -> Vec<i32> {
+    count_multiply();
+    matmul_wide(|acc, _| match mode {
+        OverflowMode::Wrap => acc as i32,
+        OverflowMode::Saturate => acc.clamp(i32::MIN as i128, i32::MAX as i128) as i32,
+        OverflowMode::Checked => {
+            i32::try_from(acc).unwrap_or_else(|_| panic!("output {} overflows i32", acc))
+        }
+    });
+    get_output()
+}
+
+#[candid_method(update)]
+#[update]
+fn multiply_heap_scaled(scale: i32) -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    count_multiply();
+    matmul_wide(|acc, _| acc.wrapping_mul(scale as i128) as i32);
+    Ok(())
+}
+
+/// Requantizes each wide accumulator with a rounding right shift,
+/// `(acc + (1 << (shift - 1))) >> shift`, as fixed-point inference kernels do.
+/// A `shift` of zero stores the accumulator unchanged.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_requant(shift: u32) -> Result<(), MatMulError> {
+    assert!(shift < 64, "shift must be less than 64, got {}", shift);
+    check_heap_shape(1)?;
+    count_multiply();
+    matmul_wide(|acc, _| {
+        if shift == 0 {
+            acc as i32
+        } else {
+            ((acc + (1 << (shift - 1))) >> shift) as i32
+        }
+    });
+    Ok(())
+}
+
+/// Like `multiply_heap`, but clamps every element of `A` and `b` to
+/// `[lo, hi]` as it is read, so a few outliers can't dominate. The bias isn't
+/// clamped, and the stored matrix is unchanged.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_clamped_inputs(lo: i32, hi: i32) -> Result<(), MatMulError> {
+    assert!(lo <= hi, "lo {} is greater than hi {}", lo, hi);
+    check_heap_shape(1)?;
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        for (i, out) in data.out_back.iter_mut().enumerate() {
+            let row = &data.a[i * n..(i + 1) * n];
+            *out = row.iter().zip(&data.b).fold(data.bias[i], |acc, (&a, &b)| {
+                acc.wrapping_add(a.clamp(lo, hi).wrapping_mul(b.clamp(lo, hi)))
+            });
+        }
+        data.publish_output();
+    });
+    Ok(())
+}
+
+/// Folds a new multiply into the output as an exponential moving average,
+/// `out = (alpha_num * new + (alpha_den - alpha_num) * out) / alpha_den`,
+/// where `new` is what `multiply_heap` would output, wrapped to `i32` like it,
+/// so the average tracks the outputs clients see. The blend itself is exact,
+/// and the division truncates.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_ema(alpha_num: u32, alpha_den: u32) -> Result<(), MatMulError> {
+    assert!(alpha_den > 0, "alpha_den must be positive");
+    assert!(
+        alpha_num <= alpha_den,
+        "alpha_num {} is greater than alpha_den {}",
+        alpha_num,
+        alpha_den
+    );
+    check_heap_shape(1)?;
+    count_multiply();
+    let (num, den) = (alpha_num as i128, alpha_den as i128);
+    matmul_wide(|acc, prev| {
+        let new = acc as i32 as i128;
+        ((num * new + (den - num) * prev as i128) / den) as i32
+    });
+    Ok(())
+}
+
+/// Multiplies the block of `A` with rows `[row_start, row_end)` and columns
+/// `[col_start, col_end)` by `b[col_start..col_end]`, for tiled computation,
+/// and adds the bias of each row. Only outputs `[row_start, row_end)` change.
+#[candid_method(update)]
+#[update]
+fn multiply_submatrix(
+    row_start: u64,
+    row_end: u64,
+    col_start: u64,
+    col_end: u64,
+) -> Result<(), MatMulError> {
+    check_heap_shape(1)?;
+    let (n, d) = DATA.with(|data| {
+        let data = data.borrow();
+        (data.b.len(), data.out.len())
+    });
+    let (row_start, row_end) = (row_start as usize, row_end as usize);
+    let (col_start, col_end) = (col_start as usize, col_end as usize);
+    assert!(
+        row_start <= row_end && row_end <= d,
+        "invalid rows [{}, {}) for d = {}",
+        row_start,
+        row_end,
+        d
+    );
+    assert!(
+        col_start <= col_end && col_end <= n,
+        "invalid columns [{}, {}) for n = {}",
+        col_start,
+        col_end,
+        n
+    );
+    count_multiply();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let b = &data.b[col_start..col_end];
+        data.out_back.copy_from_slice(&data.out);
+        for i in row_start..row_end {
+            let row = &data.a[i * n + col_start..i * n + col_end];
+            data.out_back[i] = row.iter().zip(b).fold(data.bias[i], |acc, (&a, &b)| {
+                acc.wrapping_add(a.wrapping_mul(b))
+            });
+        }
+        data.publish_output();
+    });
+    Ok(())
+}
+
+/// Multiplies as if only the first `b_len` elements of `b` were set and the
+/// rest were zero, skipping the zero tail entirely.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_padded(b_len: u64) -> Result<(), MatMulError> {
+    let (n, d) = DATA.with(|data| {
+        let data = data.borrow();
+        (data.b.len() as u64, data.out.len() as u64)
+    });
+    assert!(b_len <= n, "b_len {} is greater than n = {}", b_len, n);
+    multiply_submatrix(0, d, 0, b_len)
+}
+
+/// Pads `n` up to the next multiple of 4 by appending zeros to every row of
+/// `A` and to `b`, so the `v128` loops never need a tail. The extra products
+/// are zero, so outputs don't change. Keeps the bias and `x`; the output and
+/// `out_transpose` are cleared, as by a reshape.
+#[candid_method(update)]
+#[update]
+fn pad_to_simd_width() {
+    let (d, a, b, bias, x) = DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        let padded_n = n.next_multiple_of(4);
+        let mut a = Vec::with_capacity(padded_n * d);
+        for i in 0..d {
+            a.extend_from_slice(&data.a[i * n..(i + 1) * n]);
+            a.resize((i + 1) * padded_n, 0);
+        }
+        let mut b = data.b.clone();
+        b.resize(padded_n, 0);
+        (d, a, b, data.bias.clone(), data.x.clone())
+    });
+    configure(d, a, b);
+    set_bias(bias);
+    DATA.with(|data| data.borrow_mut().x = x);
+}
+
+const STATE_MAGIC: &[u8; 4] = b"MMST";
+const STATE_VERSION: u32 = 1;
+// Magic, version, `n` and `d`.
+const STATE_HEADER_LEN: usize = 4 + 4 + 8 + 8;
+
+/// Serializes the current matrix as a header (magic, format version, `n` and
+/// `d`) followed by `A`, `b` and the last output, all little-endian. Restore
+/// it with `import_state`.
+#[candid_method(query)]
+#[query]
+fn export_state() -> Vec<u8> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        let mut bytes = Vec::with_capacity(STATE_HEADER_LEN + (n * d + n + d) * 4);
+        bytes.extend_from_slice(STATE_MAGIC);
+        bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(n as u64).to_le_bytes());
+        bytes.extend_from_slice(&(d as u64).to_le_bytes());
+        for val in data
+            .a
+            .iter()
+            .chain(&data.b)
+            .chain(data.current_output().iter())
+        {
+            bytes.extend_from_slice(&val.to_le_bytes());
+        }
+        bytes
+    })
+}
+
+/// Replaces the current matrix with one serialized by `export_state`.
+#[candid_method(update)]
+#[update]
+fn import_state(bytes: Vec<u8>) {
+    assert!(
+        bytes.len() >= STATE_HEADER_LEN && &bytes[..4] == STATE_MAGIC,
+        "not an exported state"
+    );
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    assert_eq!(
+        version, STATE_VERSION,
+        "unsupported state version {}",
+        version
+    );
+    let n = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+    let d = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
+    // The shape comes from the caller, so it goes through the same checks as
+    // `init` before anything is computed from it.
+    let (Ok(n), Ok(d)) = (usize::try_from(n), usize::try_from(d)) else {
+        trap(&MatMulError::ShapeTooLarge { n, d }.to_string());
+    };
+    trap_on_error(check_shape(n, d));
+    let body = &bytes[STATE_HEADER_LEN..];
+    let len = (n * d)
+        .checked_add(n + d)
+        .and_then(|elems| elems.checked_mul(4));
+    assert!(
+        len == Some(body.len()),
+        "state of shape n = {}, d = {} has the wrong length",
+        n,
+        d
+    );
+
+    let mut vals = body
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()));
+    let a = vals.by_ref().take(n * d).collect();
+    let b = vals.by_ref().take(n).collect();
+    let out = vals.collect();
+    configure(d, a, b);
+    DATA.with(|data| data.borrow_mut().out = out);
+    output_changed();
+}
+
+/// The current `A` and `b` with a freshly computed `A·b`, for checking other
+/// implementations against this one. The bias isn't part of the vector, so
+/// the expected output excludes it.
+#[candid_method(query)]
+#[query]
+fn export_test_vector() -> TestVector {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let n = data.b.len();
+        let expected_out = (0..data.out.len())
+            .map(|i| {
+                data.a[i * n..(i + 1) * n]
+                    .iter()
+                    .zip(&data.b)
+                    .fold(0_i32, |acc, (&a, &b)| acc.wrapping_add(a.wrapping_mul(b)))
+            })
+            .collect();
+        TestVector {
+            n: n as u64,
+            d: data.out.len() as u64,
+            a: data.a.clone(),
+            b: data.b.clone(),
+            expected_out,
+        }
+    })
+}
+
+/// For each output, whether its dot product plus its bias exceeds
+/// `threshold`.
+///
+/// Accumulation of a row stops as soon as its partial sum passes `threshold`.
+/// That is only sound when no later product can be negative, so `A` and `b`
+/// must be non-negative.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_threshold(threshold: i32) -> Vec<bool> {
+    count_multiply();
+    DATA.with(|data| {
+        let data = data.borrow();
+        let n = data.b.len();
+        assert!(
+            data.a.iter().chain(&data.b).all(|&val| val >= 0),
+            "multiply_heap_threshold requires non-negative inputs"
+        );
+
+        (0..data.out.len())
+            .map(|i| {
+                let row = &data.a[i * n..(i + 1) * n];
+                let mut acc = data.bias[i] as i64;
+                if acc > threshold as i64 {
+                    return true;
+                }
+                for (&a, &b) in row.iter().zip(&data.b) {
+                    acc += a as i64 * b as i64;
+                    if acc > threshold as i64 {
+                        return true;
+                    }
+                }
+                false
+            })
+            .collect()
+    })
+}
+
+/// Saves the current matrix, including its last output, under `name`, on the
+/// heap and in a stable region of its own. Saving over an existing name, or
+/// dropping it, frees its region for later matrices.
+#[candid_method(update)]
+#[update]
+fn save_matrix(name: String) {
+    drop_matrix(name.clone());
+    let data = DATA.with(|data| {
+        let data = data.borrow();
+        Data {
+            out: data.current_output().into_owned(),
+            last_path: OutputPath::Heap,
+            ..data.clone()
+        }
+    });
+    let vals: Vec<i32> = [&data.a, &data.b, &data.out]
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+    let len = vals.len() as u64 * 4;
+    let floor = Layout::current_of(&data).size();
+    let addr = NAMED_ALLOC.with(|alloc| alloc.borrow_mut().alloc(len, floor));
+    let pages = (addr + len).div_ceil(WASM_PAGE_SIZE);
+    if pages > stable_size() {
+        stable_grow(pages - stable_size()).unwrap();
+    }
+    write_stable_i32s(addr, &vals);
+    NAMED.with(|named| named.borrow_mut().insert(name, Named { data, addr, len }));
+}
+
+/// Forgets the matrix saved as `name`, if any, freeing its stable region.
+#[candid_method(update)]
+#[update]
+fn drop_matrix(name: String) {
+    if let Some(old) = NAMED.with(|named| named.borrow_mut().remove(&name)) {
+        NAMED_ALLOC.with(|alloc| alloc.borrow_mut().free(old.addr, old.len));
+    }
+}
+
+/// The first byte of stable memory used by a named matrix, or by the runs of
+/// `init_rle`, if any.
+fn named_start() -> Option<u64> {
+    let rle = RLE_A.with(|rle| rle.borrow().as_ref().map(|rle| rle.addr));
+    NAMED
+        .with(|named| named.borrow().values().map(|named| named.addr).min())
+        .into_iter()
+        .chain(rle)
+        .min()
+}
+
+/// Uses the output of the matrix saved as `source_name` as the current `b`,
+/// e.g. to feed one layer's activations into the next.
+#[candid_method(update)]
+#[update]
+fn set_b_from_output(source_name: String) {
+    let b = NAMED.with(|named| match named.borrow().get(&source_name) {
+        Some(source) => source.data.out.clone(),
+        None => trap(&format!("no matrix named {:?}", source_name)),
+    });
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let n = data.b.len();
+        assert_eq!(
+            b.len(),
+            n,
+            "output of {:?} has length {} but b has length {}",
+            source_name,
+            b.len(),
+            n
+        );
+        write_stable_i32s(Layout::current_of(&data).b_addr(), &b);
+        data.b = b;
+    });
+    invalidate_prepared_b();
+    restart_partial_multiplies();
+}
+
+/// Replaces `b`, which must keep its length `n`.
+#[candid_method(update)]
+#[update]
+fn set_b(b: Vec<i32>) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let n = data.b.len();
+        assert_eq!(b.len(), n, "b must have length {}, got {}", n, b.len());
+        write_stable_i32s(Layout::current_of(&data).b_addr(), &b);
+        data.b = b;
+    });
+    invalidate_prepared_b();
+    restart_partial_multiplies();
+}
+
+/// Replaces `A` and `b` in one call from `bytes`: the `n * d` little-endian
+/// `i32`s of `A` followed by the `n` of `b`, keeping the current shape.
+#[candid_method(update)]
+#[update]
+fn load_all_bytes(bytes: Vec<u8>) {
+    let layout = Layout::current();
+    let (n, d) = (layout.n as usize, layout.d as usize);
+    let len = (n * d + n) * 4;
+    assert_eq!(
+        bytes.len(),
+        len,
+        "bytes must have length {} for n = {}, d = {}, got {}",
+        len,
+        n,
+        d,
+        bytes.len()
+    );
+    let mut vals = bytes
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()));
+    let a = vals.by_ref().take(n * d).collect();
+    let b = vals.collect();
+    configure(d, a, b);
+}
+
+/// Sets every element of `A` to `value`, in both heap and stable memory.
+#[candid_method(update)]
+#[update]
+fn fill_a(value: i32) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        data.a.fill(value);
+        write_stable_i32s(Layout::current_of(&data).a_addr(), &data.a);
+    });
+    restart_partial_multiplies();
+}
+
+/// Row `row` of `A`, which must be below `d`.
+#[candid_method(query)]
+#[query]
+fn get_a_row(row: u32) -> Vec<i32> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let (n, d) = (data.b.len(), data.out.len());
+        assert!(
+            (row as usize) < d,
+            "row {} is out of range for d = {}",
+            row,
+            d
+        );
+        data.a[row as usize * n..(row as usize + 1) * n].to_vec()
+    })
+}
+
+/// Overwrites column `col` of `A` with the last output (as `get_output` reads
+/// it), one element per row, in both heap and stable memory. Iterative schemes
+/// can then refine `A` in place. `col` must be below `n`.
+#[candid_method(update)]
+#[update]
+fn write_output_to_a_column(col: u64) {
+    let out = get_output();
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let layout = Layout::current_of(&data);
+        assert!(
+            col < layout.n,
+            "column {} is out of range for n = {}",
+            col,
+            layout.n
+        );
+        assert_eq!(
+            out.len() as u64,
+            layout.d,
+            "the output has length {}, but columns have length {}",
+            out.len(),
+            layout.d
+        );
+        for (i, val) in out.into_iter().enumerate() {
+            let index = i as u64 * layout.n + col;
+            data.a[index as usize] = val;
+            stable_write(layout.a_addr() + index * 4, &val.to_le_bytes());
+        }
+    });
+    restart_partial_multiplies();
+}
+
+/// Zeroes the given rows of `A`, so that their outputs drop to zero while the
+/// others are unchanged. Every index must be below `d`.
+#[candid_method(update)]
+#[update]
+fn mask_rows(rows: Vec<u32>) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let layout = Layout::current_of(&data);
+        let (n, d) = (layout.n as usize, layout.d);
+        for &row in &rows {
+            assert!(
+                (row as u64) < d,
+                "row {} is out of range for d = {}",
+                row,
+                d
+            );
+        }
+        let zeros = vec![0; n];
+        for row in rows {
+            let start = row as usize * n;
+            data.a[start..start + n].fill(0);
+            write_stable_i32s(layout.a_addr() + start as u64 * 4, &zeros);
+        }
+    });
+    restart_partial_multiplies();
+}
+
+/// Scales every element of `A` by `2^bits`: an arithmetic left shift for
+/// positive `bits`, and a right shift, rounding towards negative infinity, for
+/// negative ones. Traps without changing `A` if a left shift would overflow.
+#[candid_method(update)]
+#[update]
+fn shift_a(bits: i32) {
+    assert!(
+        bits.unsigned_abs() < 32,
+        "bits must be in (-32, 32), got {}",
+        bits
+    );
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let shifted: Vec<i32> = data
+            .a
+            .iter()
+            .map(|&a| {
+                if bits >= 0 {
+                    let wide = (a as i64) << bits;
+                    i32::try_from(wide)
+                        .unwrap_or_else(|_| panic!("{} << {} overflows i32", a, bits))
+                } else {
+                    a >> -bits
+                }
+            })
+            .collect();
+        write_stable_i32s(Layout::current_of(&data).a_addr(), &shifted);
+        data.a = shifted;
+    });
+    restart_partial_multiplies();
+}
+
+/// Sets the bias, one element per row, that every multiply of `A·b` adds to
+/// its outputs. `dot`, `dot_rows`, `multiply_transpose` and `power_iterate`
+/// compute other products and ignore it.
+#[candid_method(update)]
+#[update]
+fn set_bias(bias: Vec<i32>) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let d = data.out.len();
+        assert_eq!(
+            bias.len(),
+            d,
+            "bias must have length {}, got {}",
+            d,
+            bias.len()
+        );
+        write_stable_i32s(Layout::current_of(&data).bias_addr(), &bias);
+        data.bias = bias;
+    });
+    restart_partial_multiplies();
+}
+
+/// Sets every element of `b` to `value`, in both heap and stable memory.
+#[candid_method(update)]
+#[update]
+fn fill_b(value: i32) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        data.b.fill(value);
+        write_stable_i32s(Layout::current_of(&data).b_addr(), &data.b);
+    });
+    invalidate_prepared_b();
+    restart_partial_multiplies();
+}
+
+/// Stages `out` as the output, on the heap and in stable memory, without
+/// multiplying, so that clients can test what consumes it. It must have the
+/// length `d`.
+#[candid_method(update)]
+#[update]
+fn set_output(out: Vec<i32>) {
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        let d = data.out.len();
+        assert_eq!(
+            out.len(),
+            d,
+            "out must have length {}, got {}",
+            d,
+            out.len()
+        );
+        write_stable_i32s(Layout::current_of(&data).out_addr(), &out);
+        data.out = out;
+        output_changed();
+        data.last_path = OutputPath::Heap;
+    });
+}
+
+/// The output of the last multiply, from wherever it wrote it: the heap
+/// output after `multiply_heap` and the like, or stable memory after
+/// `multiply_stable` and the others writing the stable `out` region. The
+/// other output queries read it from the same place.
+#[candid_method(query)]
+#[query]
+fn get_output() -> Vec<i32> {
+    DATA.with(|data| data.borrow().current_output().into_owned())
+}
+
+/// Where `get_output` currently reads from: `"heap"` or `"stable"`.
+#[candid_method(query)]
+#[query]
+fn last_path() -> String {
+    DATA.with(|data| data.borrow().last_path.name().to_string())
+}
+
+/// Sets up the `f32` matrix like `init` does the `i32` one.
+#[candid_method(update)]
+#[update]
+fn init_f32(n: usize, d: usize) {
+    trap_on_error(check_shape(n, d));
+    DATA_F32.with(|data| {
+        *data.borrow_mut() = FloatData {
+            a: (0..n * d).map(|i| i as f32).collect(),
+            b: (0..n).map(|i| i as f32).collect(),
+            out: vec![0.0; d],
+        }
+    });
+}
+
+/// Replaces the `f32` `A`, which must keep its `d × n` shape.
+#[candid_method(update)]
+#[update]
+fn set_a_f32(a: Vec<f32>) {
+    DATA_F32.with(|data| {
+        let mut data = data.borrow_mut();
+        let len = data.b.len() * data.out.len();
+        assert_eq!(a.len(), len, "a must have length {}, got {}", len, a.len());
+        data.a = a;
+    });
+}
+
+/// Replaces the `f32` `b`, which must keep its length `n`.
+#[candid_method(update)]
+#[update]
+fn set_b_f32(b: Vec<f32>) {
+    DATA_F32.with(|data| {
+        let mut data = data.borrow_mut();
+        let n = data.b.len();
+        assert_eq!(b.len(), n, "b must have length {}, got {}", n, b.len());
+        data.b = b;
+    });
+}
+
+#[candid_method(update)]
+#[update]
+fn multiply_heap_f32() -> Result<(), MatMulError> {
+    check_f32_shape()?;
+    count_multiply();
+    matmul_f32(|products| products.iter().sum());
+    Ok(())
+}
+
+/// Like `multiply_heap_f32`, but sums each row's products pairwise: halves
+/// are summed recursively down to runs of `block_size`, which are summed in
+/// order. The rounding error then grows with `log(n)` rather than `n`.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_f32_blocked(block_size: u32) -> Result<(), MatMulError> {
+    assert!(block_size > 0, "block_size must be positive");
+    check_f32_shape()?;
+    count_multiply();
+    matmul_f32(|products| pairwise_sum(products, block_size as usize));
+    Ok(())
+}
+
+/// Like `multiply_heap_f32`, but with a guaranteed summation order: each
+/// output is `((a[i][0] * b[0] + a[i][1] * b[1]) + a[i][2] * b[2]) + ...`,
+/// starting from `0.0` and adding one rounded product at a time from left to
+/// right, with no fused multiply-adds or regrouping. The result is then
+/// bit-for-bit reproducible across canister versions, whatever the other
+/// kernels' grouping.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_f32_ordered() -> Result<(), MatMulError> {
+    check_f32_shape()?;
+    count_multiply();
+    matmul_f32(sequential_sum);
+    Ok(())
+}
+
+fn sequential_sum(vals: &[f32]) -> f32 {
+    let mut acc = 0.0;
+    for val in vals {
+        acc += val;
+    }
+    acc
+}
+
+fn pairwise_sum(vals: &[f32], block_size: usize) -> f32 {
+    if vals.len() <= block_size {
+        vals.iter().sum()
+    } else {
+        let (low, high) = vals.split_at(vals.len() / 2);
+        pairwise_sum(low, block_size) + pairwise_sum(high, block_size)
+    }
+}
+
+/// Multiplies the `f32` matrix, reducing each row's products with `sum`.
+/// Each product is rounded to `f32` on its own, so the order of accumulation
+/// is entirely up to `sum`.
+fn matmul_f32(sum: impl Fn(&[f32]) -> f32) {
+    DATA_F32.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        let mut products = vec![0.0; n];
+        for (i, out) in data.out.iter_mut().enumerate() {
+            let row = &data.a[i * n..(i + 1) * n];
+            for ((product, a), b) in products.iter_mut().zip(row).zip(&data.b) {
+                *product = a * b;
+            }
+            *out = sum(&products);
+        }
+    });
+}
+
+#[candid_method(query)]
+#[query]
+fn get_output_f32() -> Vec<f32> {
+    DATA_F32.with(|data| data.borrow().out.clone())
+}
+
+/// Whether any element of the `f32` output is NaN or infinite, a cheap sign
+/// that an iterative computation has blown up.
+#[candid_method(query)]
+#[query]
+fn output_has_nonfinite() -> bool {
+    DATA_F32.with(|data| data.borrow().out.iter().any(|out| !out.is_finite()))
+}
+
+/// Whether every element of the `f32` output is within `tol` of `reference`.
+/// Exact equality is too strict between floating-point kernels that sum in
+/// different orders. A NaN output is never close to anything.
+#[candid_method(query)]
+#[query]
+fn output_close_to(reference: Vec<f32>, tol: f32) -> bool {
+    DATA_F32.with(|data| {
+        let out = &data.borrow().out;
+        assert_eq!(
+            reference.len(),
+            out.len(),
+            "reference must have length {}, got {}",
+            out.len(),
+            reference.len()
+        );
+        out.iter()
+            .zip(&reference)
+            .all(|(out, reference)| (out - reference).abs() <= tol)
+    })
+}
+
+/// Whether the `i32` multiply could overflow for some `A` and `b` with the
+/// current inputs' ranges: whether `n * max|a| * max|b| + max|bias|` exceeds
+/// `i32::MAX`. If it doesn't, no dot product can overflow; if it does, the
+/// `i64` path is the safe choice.
+#[candid_method(query)]
+#[query]
+fn check_overflow_risk() -> bool {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let max_abs =
+            |vals: &[i32]| vals.iter().map(|val| val.unsigned_abs()).max().unwrap_or(0) as u128;
+        let worst =
+            data.b.len() as u128 * max_abs(&data.a) * max_abs(&data.b) + max_abs(&data.bias);
+        worst > i32::MAX as u128
+    })
+}
+
+/// Sets up the `i64` matrix like `init` does the `i32` one.
+#[candid_method(update)]
+#[update]
+fn init_i64(n: usize, d: usize) {
+    trap_on_error(check_shape(n, d));
+    DATA_I64.with(|data| {
+        *data.borrow_mut() = WideData {
+            a: (0..(n * d) as i64).collect(),
+            b: (0..n as i64).collect(),
+            out: vec![0; d],
+        }
+    });
+}
+
+/// Replaces the `i64` `A`, which must keep its `d × n` shape.
+#[candid_method(update)]
+#[update]
+fn set_a_i64(a: Vec<i64>) {
+    DATA_I64.with(|data| {
+        let mut data = data.borrow_mut();
+        let len = data.b.len() * data.out.len();
+        assert_eq!(a.len(), len, "a must have length {}, got {}", len, a.len());
+        data.a = a;
+    });
+}
+
+/// Replaces the `i64` `b`, which must keep its length `n`.
+#[candid_method(update)]
+#[update]
+fn set_b_i64(b: Vec<i64>) {
+    DATA_I64.with(|data| {
+        let mut data = data.borrow_mut();
+        let n = data.b.len();
+        assert_eq!(b.len(), n, "b must have length {}, got {}", n, b.len());
+        data.b = b;
+    });
+}
+
+/// Multiplies the `i64` matrix, accumulating in an `i128` so that products
+/// beyond `i64` are still summed exactly. Sums that don't fit an `i64`
+/// saturate.
+#[candid_method(update)]
+#[update]
+fn multiply_heap_i64() -> Result<(), MatMulError> {
+    check_i64_shape()?;
+    count_multiply();
+    DATA_I64.with(|data| {
+        let mut data = data.borrow_mut();
+        let data = &mut *data;
+        let n = data.b.len();
+        for (i, out) in data.out.iter_mut().enumerate() {
+            let row = &data.a[i * n..(i + 1) * n];
+            let acc = row.iter().zip(&data.b).fold(0_i128, |acc, (&a, &b)| {
+                acc.saturating_add(a as i128 * b as i128)
+            });
+            *out = acc.clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+        }
+    });
+    Ok(())
+}
+
+#[candid_method(query)]
+#[query]
+fn get_output_heap_i64() -> Vec<i64> {
+    DATA_I64.with(|data| data.borrow().out.clone())
+}
+
+/// Sets up the dequantizing multiply: `a` holds the `d × n` weights,
+/// quantized to `i8` with `scale`, and `b` the `n` float activations.
+#[candid_method(update)]
+#[update]
+fn init_i8_f32(a: Vec<i8>, b: Vec<f32>, scale: f32) {
+    assert!(!b.is_empty(), "b must not be empty");
+    assert_eq!(
+        a.len() % b.len(),
+        0,
+        "a must have a multiple of {} elements, got {}",
+        b.len(),
+        a.len()
+    );
+    DATA_I8_F32.with(|data| *data.borrow_mut() = DequantData { a, scale, b });
+}
+
+/// Multiplies the `i8` weights by the `f32` activations, dequantizing each
+/// weight as it goes: output `i` is the sum over `j` of
+/// `(a[i][j] as f32 * scale) * b[j]`, accumulated in order in an `f32`.
+/// Returns the `d` outputs.
+#[candid_method(update)]
+#[update]
+fn multiply_i8_f32() -> Vec<f32> {
+    count_multiply();
+    DATA_I8_F32.with(|data| {
+        let data = data.borrow();
+        data.a
+            .chunks(data.b.len())
+            .map(|row| {
+                let mut acc = 0.0;
+                for (&a, b) in row.iter().zip(&data.b) {
+                    acc += (a as f32 * data.scale) * b;
+                }
+                acc
+            })
+            .collect()
+    })
+}
+
+/// Sets up the int8 multiply with the `d × n` `a` and the `n` `b`, keeping
+/// the zero points.
+#[candid_method(update)]
+#[update]
+fn init_int8(a: Vec<i8>, b: Vec<i8>) {
+    assert!(!b.is_empty(), "b must not be empty");
+    assert_eq!(
+        a.len() % b.len(),
+        0,
+        "a must have a multiple of {} elements, got {}",
+        b.len(),
+        a.len()
+    );
+    DATA_INT8.with(|data| {
+        let mut data = data.borrow_mut();
+        data.a = a;
+        data.b = b;
+    });
+}
+
+/// Sets the zero points of the int8 `a` and `b`, both 0 by default.
+#[candid_method(update)]
+#[update]
+fn set_zero_points(za: i32, zb: i32) {
+    DATA_INT8.with(|data| {
+        let mut data = data.borrow_mut();
+        data.za = za;
+        data.zb = zb;
+    });
+}
+
+/// Multiplies the int8 inputs as the values they quantize: output `i` is the
+/// sum over `j` of `(a[i][j] - za) * (b[j] - zb)`, wrapping like
+/// `multiply_heap`. Rather than subtracting in the inner loop, it expands
+/// this to `Σ a·b - zb·Σ a - za·Σ b + n·za·zb`, so the loop is a plain `i8`
+/// dot product and `Σ b` is only summed once. Returns the `d` outputs.
+#[candid_method(update)]
+#[update]
+fn multiply_int8() -> Vec<i32> {
+    count_multiply();
+    DATA_INT8.with(|data| {
+        let data = data.borrow();
+        let (za, zb) = (data.za, data.zb);
+        let n = data.b.len() as i32;
+        let sum_b = data
+            .b
+            .iter()
+            .fold(0_i32, |sum, &b| sum.wrapping_add(b as i32));
+        let offset = n
+            .wrapping_mul(za)
+            .wrapping_mul(zb)
+            .wrapping_sub(za.wrapping_mul(sum_b));
+        data.a
+            .chunks(data.b.len())
+            .map(|row| {
+                let (mut dot, mut sum_a) = (0_i32, 0_i32);
+                for (&a, &b) in row.iter().zip(&data.b) {
+                    dot = dot.wrapping_add(a as i32 * b as i32);
+                    sum_a = sum_a.wrapping_add(a as i32);
+                }
+                dot.wrapping_sub(zb.wrapping_mul(sum_a))
+                    .wrapping_add(offset)
+            })
+            .collect()
+    })
+}
+
+/// Number of multiplies served so far, across upgrades.
+#[candid_method(query)]
+#[query]
+fn multiply_count() -> u64 {
+    DATA.with(|data| data.borrow().call_count)
+}
+
+/// Number of output elements returned per `output_cursor` call (256 KiB of
+/// `i32`s), well within the message size limit.
+const OUTPUT_CURSOR_BATCH: usize = 64 * 1024;
+
+/// Bits of an `output_cursor` token holding the element offset. The bits
+/// above hold the output generation the cursor started on.
+const OUTPUT_CURSOR_OFFSET_BITS: u32 = 40;
+
+/// Returns the next batch of the last output starting at `token`, and the
+/// token to continue from. Pass `0` to start from the beginning; a returned
+/// token of `0` means the output has been fully read. Traps if the output has
+/// changed since the cursor started, rather than mixing two outputs.
+#[candid_method(query)]
+#[query]
+fn output_cursor(token: u64) -> (Vec<i32>, u64) {
+    let generation = OUTPUT_GENERATION.with(|generation| generation.get())
+        & (u64::MAX >> OUTPUT_CURSOR_OFFSET_BITS);
+    if token != 0 && token >> OUTPUT_CURSOR_OFFSET_BITS != generation {
+        trap("the output has changed since this cursor started; start again from token 0");
+    }
+    DATA.with(|data| {
+        let data = data.borrow();
+        let out = data.current_output();
+        let start = (token & ((1 << OUTPUT_CURSOR_OFFSET_BITS) - 1)) as usize;
+        assert!(
+            start <= out.len(),
+            "token {} is past the end of the output ({} elements)",
+            token,
+            out.len()
+        );
+        let end = out.len().min(start + OUTPUT_CURSOR_BATCH);
+        let next = if end == out.len() {
+            0
+        } else {
+            generation << OUTPUT_CURSOR_OFFSET_BITS | end as u64
+        };
+        (out[start..end].to_vec(), next)
+    })
+}
+
+/// Whether every element of the last output is zero, which usually means the
+/// layer is misconfigured.
+#[candid_method(query)]
+#[query]
+fn output_is_zero() -> bool {
+    DATA.with(|data| data.borrow().current_output().iter().all(|&val| val == 0))
+}
+
+/// Copies the last output into the snapshot region of stable memory, for
+/// `diff_snapshot` to compare later outputs against. Reshaping clears the
+/// snapshot to zeros.
+#[candid_method(update)]
+#[update]
+fn snapshot_output() {
+    DATA.with(|data| {
+        let data = data.borrow();
+        write_stable_i32s(
+            Layout::current_of(&data).snapshot_addr(),
+            &data.current_output(),
+        );
+    });
+}
+
+/// The indices, in order, where the last output differs from the one saved by
+/// `snapshot_output`, to catch data changes or nondeterminism between runs.
+#[candid_method(query)]
+#[query]
+fn diff_snapshot() -> Vec<u64> {
+    let layout = Layout::current();
+    diff_output(read_stable_i32s(layout.snapshot_addr(), layout.d as usize))
+}
+
+/// The indices, in order, where the last output differs from `other`, which
+/// must have the same length.
+#[candid_method(query)]
+#[query]
+fn diff_output(other: Vec<i32>) -> Vec<u64> {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let out = data.current_output();
+        assert_eq!(
+            other.len(),
+            out.len(),
+            "other must have length {}, got {}",
+            out.len(),
+            other.len()
+        );
+        out.iter()
+            .zip(&other)
+            .enumerate()
+            .filter(|(_, (out, other))| out != other)
+            .map(|(i, _)| i as u64)
+            .collect()
+    })
+}
+
+/// The `k` smallest outputs with their indices, in ascending order with ties
+/// by index, or every output if `k > d`. Selects the `k` before sorting them,
+/// so only those are sorted.
+#[candid_method(query)]
+#[query]
+fn output_bottomk(k: u64) -> Vec<(u64, i32)> {
+    DATA.with(|data| {
+        let mut outputs: Vec<(u64, i32)> = data
+            .borrow()
+            .current_output()
+            .iter()
+            .enumerate()
+            .map(|(i, &val)| (i as u64, val))
+            .collect();
+        let k = (k as usize).min(outputs.len());
+        if k == 0 {
+            return Vec::new();
+        }
+        outputs.select_nth_unstable_by_key(k - 1, |&(i, val)| (val, i));
+        outputs.truncate(k);
+        outputs.sort_unstable_by_key(|&(i, val)| (val, i));
+        outputs
+    })
+}
+
+/// The last output as comma-separated values on one line, for quick
+/// inspection.
+#[candid_method(query)]
+#[query]
+fn output_csv() -> String {
+    DATA.with(|data| {
+        let vals: Vec<String> = data
+            .borrow()
+            .current_output()
+            .iter()
+            .map(i32::to_string)
+            .collect();
+        vals.join(",")
+    })
+}
+
+/// Counts the last output's values in `bins` equal-width bins covering
+/// `[min, max]`. Values outside the range are counted in the first or last
+/// bin.
+#[candid_method(query)]
+#[query]
+fn output_histogram(bins: u32, min: i32, max: i32) -> Vec<u64> {
+    assert!(bins > 0, "bins must be positive");
+    assert!(min <= max, "min {} is greater than max {}", min, max);
+    let range = max as i64 - min as i64 + 1;
+    let mut counts = vec![0; bins as usize];
+    DATA.with(|data| {
+        for &val in data.borrow().current_output().iter() {
+            let offset = (val.clamp(min, max) as i64) - min as i64;
+            counts[(offset * bins as i64 / range) as usize] += 1;
+        }
+    });
+    counts
+}
+
+/// The index and signed value of the last output's element with the largest
+/// magnitude, taking the first on ties.
+#[candid_method(query)]
+#[query]
+fn output_max_abs() -> (u64, i32) {
+    DATA.with(|data| {
+        let out = data.borrow().current_output().into_owned();
+        assert!(!out.is_empty(), "the output is empty");
+        // `i32::MIN.abs()` overflows `i32`.
+        let (index, &val) = out
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, &val)| (val as i64).abs())
+            .unwrap();
+        (index as u64, val)
+    })
+}
+
+/// A 64-bit FNV-1a hash of the candid service definition, so that clients
+/// can detect an incompatible interface before calling. Unlike `std`'s
+/// hashers, FNV-1a is fixed, so the hash only changes with the interface.
+#[candid_method(query)]
+#[query]
+fn interface_hash() -> u64 {
+    fnv1a(candid_interface().as_bytes())
+}
+
+/// A 64-bit FNV-1a hash of `n`, `d` and the little-endian bytes of `A` and
+/// `b`, in that order. Canisters holding the same matrix report the same
+/// fingerprint, so comparing them is a quick consistency check.
+#[candid_method(query)]
+#[query]
+fn fingerprint() -> u64 {
+    DATA.with(|data| {
+        let data = data.borrow();
+        let layout = Layout::current_of(&data);
+        let hash = fnv1a_extend(FNV1A_OFFSET, &layout.n.to_le_bytes());
+        let hash = fnv1a_extend(hash, &layout.d.to_le_bytes());
+        data.a
+            .iter()
+            .chain(&data.b)
+            .fold(hash, |hash, val| fnv1a_extend(hash, &val.to_le_bytes()))
+    })
+}
+
+const FNV1A_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_extend(FNV1A_OFFSET, bytes)
+}
+
+/// Continues an FNV-1a `hash` over `bytes`.
+fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// The candid service definition of this canister, from the methods
+// annotated with `candid_method` above.
+//
+// Note that `export_service` (which defines `__export_service` in the current
+// scope) needs to be called exactly once, after all `candid_method`s, as it
+// consumes the methods collected so far. So everything that needs the
+// definition (`main`, `__get_candid_interface_tmp_hack` and the test below)
+// goes through this function.
+fn candid_interface() -> String {
+    // The line below generates did types and service definition from the
+    // methods annotated with `candid_method` above. The definition is then
+    // obtained with `__export_service()`.
+    candid::export_service!();
+    __export_service()
+}
+
+// Conventional method for tooling to fetch the interface of a deployed
+// canister without its `.did` file. It is deliberately not a `candid_method`,
+// so it doesn't appear in the interface itself.
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn get_candid_interface() -> String {
+    candid_interface()
+}
+
+// When run on native this prints the candid service definition of this
+// canister.
+#[cfg(not(any(target_family = "wasm", test)))]
+fn main() {
+    std::print!("{}", candid_interface());
+}
+
+#[cfg(any(target_family = "wasm", test))]
+fn main() {}
+
+#[cfg(test)]
+fn did_file() -> String {
+    let did_path = match std::env::var("DID_PATH") {
+        Ok(v) => v,
+        Err(_e) => "matrix-multiply.did".to_string(),
+    };
+    String::from_utf8(std::fs::read(did_path).unwrap()).unwrap()
+}
+
+#[test]
+fn check_candid_file() {
+    assert_eq!(did_file(), candid_interface());
+}
+
+#[test]
+fn candid_interface_query_matches_did_file() {
+    assert_eq!(get_candid_interface(), did_file());
+}
+
+#[test]
+fn interface_hash_is_fnv1a_of_did_file() {
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(interface_hash(), fnv1a(did_file().as_bytes()));
+    assert_eq!(interface_hash(), interface_hash());
+}
+
+#[test]
+fn output_reads_only_see_completed_multiplies() {
+    let (n, d) = (64, 2);
+    configure(d, vec![1; n * d], (0..n as i32).collect());
+    let sum: i32 = (0..n as i32).sum();
+
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vec![sum; d]);
+
+    // Simulate an update that has written only the first element of the next
+    // result: the published output must still be the previous snapshot.
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        data.a = vec![2; n * d];
+        data.out_back[0] = 2 * sum;
+    });
+    assert_eq!(get_output(), vec![sum; d]);
+
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vec![2 * sum; d]);
+}
+
+#[test]
+fn multiply_heap_scaled_matches_manual_scaling() {
+    let (n, d) = (64, 3);
+    configure(
+        d,
+        (0..(n * d) as i32).map(|x| x % 7 - 3).collect(),
+        (0..n as i32).map(|x| x % 5 - 2).collect(),
+    );
+
+    multiply_heap().unwrap();
+    let unscaled = get_output();
+
+    for scale in [0, 1, -3, 1 << 20] {
+        multiply_heap_scaled(scale).unwrap();
+        let expected: Vec<i32> = unscaled.iter().map(|v| v.wrapping_mul(scale)).collect();
+        assert_eq!(get_output(), expected);
+    }
+}
+
+#[test]
+fn multiply_heap_requant_rounds_like_reference() {
+    // Rounding half up, written with Euclidean division so it is independent
+    // of the shift-based implementation.
+    fn reference(acc: i64, shift: u32) -> i32 {
+        let scale = 1_i64 << shift;
+        (acc + scale / 2).div_euclid(scale) as i32
+    }
+
+    let (n, d) = (64, 4);
+    configure(
+        d,
+        (0..(n * d) as i32).map(|x| (x * 37) % 101 - 50).collect(),
+        (0..n as i32).map(|x| (x * 13) % 17 - 9).collect(),
+    );
+    let accs: Vec<i64> = DATA.with(|data| {
+        let data = data.borrow();
+        data.a
+            .chunks(n)
+            .map(|row| {
+                row.iter()
+                    .zip(&data.b)
+                    .map(|(&a, &b)| a as i64 * b as i64)
+                    .sum()
+            })
+            .collect()
+    });
+    assert!(accs.iter().any(|&acc| acc < 0));
+
+    for shift in [0, 1, 3, 8, 31] {
+        multiply_heap_requant(shift).unwrap();
+        let expected: Vec<i32> = accs.iter().map(|&acc| reference(acc, shift)).collect();
+        assert_eq!(get_output(), expected, "shift {}", shift);
+    }
+
+    // Two `i32::MIN * i32::MIN` products sum to 2^63, past `i64::MAX`.
+    configure(1, vec![i32::MIN; 2], vec![i32::MIN; 2]);
+    multiply_heap_requant(33).unwrap();
+    assert_eq!(get_output(), vec![1 << 30]);
+}
+
+#[test]
+fn set_b_from_output_chains_named_matrices() {
+    let (n1, d1) = (64, 64);
+    let (n2, d2) = (d1, 3);
+    let a1: Vec<i32> = (0..(n1 * d1) as i32).map(|x| x % 5 - 2).collect();
+    let b1: Vec<i32> = (0..n1 as i32).map(|x| x % 3 - 1).collect();
+    let a2: Vec<i32> = (0..(n2 * d2) as i32).map(|x| x % 7 - 3).collect();
+
+    // Manual wiring: copy layer 1's output into layer 2's `b` by hand.
+    configure(d1, a1.clone(), b1.clone());
+    multiply_heap().unwrap();
+    let hidden = get_output();
+    configure(d2, a2.clone(), hidden.clone());
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    configure(d1, a1, b1);
+    multiply_heap().unwrap();
+    save_matrix("layer1".to_string());
+    configure(d2, a2, vec![0; n2]);
+    set_b_from_output("layer1".to_string());
+    multiply_heap().unwrap();
+
+    assert_eq!(get_output(), expected);
+    assert_eq!(read_stable_i32s(Layout::current().b_addr(), n2), hidden);
+}
+
+#[test]
+fn output_is_zero_detects_dead_layer() {
+    let (n, d) = (64, 2);
+    configure(d, vec![0; n * d], vec![1; n]);
+    multiply_heap().unwrap();
+    assert!(output_is_zero());
+
+    configure(d, vec![1; n * d], vec![1; n]);
+    multiply_heap().unwrap();
+    assert!(!output_is_zero());
+}
+
+#[test]
+fn multiply_stable_handles_unaligned_rows() {
+    // With n = 6, the second row of `A` starts at byte 24 and `b` at byte 72,
+    // neither of which is 16-byte aligned.
+    let (n, d) = (6, 3);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| x * 3 - 20).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| x - 2).collect();
+    let expected: Vec<i32> = a
+        .chunks(n)
+        .map(|row| row.iter().zip(&b).map(|(a, b)| a * b).sum())
+        .collect();
+    configure(d, a, b);
+
+    multiply_stable().unwrap();
+
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), expected);
+}
+
+#[test]
+fn output_cursor_drains_full_output() {
+    let d = OUTPUT_CURSOR_BATCH * 2 + 123;
+    configure(d, (0..d as i32).map(|x| x % 1000 - 500).collect(), vec![1]);
+    multiply_heap_scaled(1).unwrap();
+
+    let mut batches = 0;
+    let mut output = Vec::new();
+    let mut token = 0;
+    loop {
+        let (batch, next) = output_cursor(token);
+        assert!(batch.len() <= OUTPUT_CURSOR_BATCH);
+        output.extend(batch);
+        batches += 1;
+        if next == 0 {
+            break;
+        }
+        token = next;
+    }
+
+    assert_eq!(batches, 3);
+    assert_eq!(output, get_output());
+}
+
+#[test]
+fn stable_dot_matches_scalar_for_all_lane_widths() {
+    fn check<T: SimdLanes<LANES>, const LANES: usize>(
+        a: &[i32],
+        b: &[i32],
+        to_bytes: fn(i32) -> Vec<u8>,
+    ) {
+        let a_bytes: Vec<u8> = a.iter().flat_map(|&x| to_bytes(x)).collect();
+        let b_bytes: Vec<u8> = b.iter().flat_map(|&x| to_bytes(x)).collect();
+        // Keep `b` 16-byte aligned after `a`.
+        let b_addr = a_bytes.len().next_multiple_of(16) as u64;
+        stable_write(0, &a_bytes);
+        stable_write(b_addr, &b_bytes);
+
+        let expected: i32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+        let actual = stable_dot::<T, LANES>(0, b_addr, a.len() as u64);
+        assert_eq!(actual, expected, "{} lanes, n = {}", LANES, a.len());
+    }
+
+    stable_grow(1).unwrap();
+    for n in [0, 1, 15, 16, 17, 40, 67] {
+        let a: Vec<i32> = (0..n).map(|x| (x * 37) % 255 - 128).collect();
+        let b: Vec<i32> = (0..n).map(|x| 127 - (x * 11) % 256).collect();
+        check::<i32, 4>(&a, &b, |x| x.to_le_bytes().to_vec());
+        check::<i16, 8>(&a, &b, |x| (x as i16).to_le_bytes().to_vec());
+        check::<i8, 16>(&a, &b, |x| (x as i8).to_le_bytes().to_vec());
+    }
+}
+
+#[test]
+fn prepare_b_simd_reuses_b_across_rows() {
+    let (n, d) = (10, 8);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| x % 9 - 4).collect();
+    configure(d, a, (0..n as i32).map(|x| 3 - x).collect());
+    let out_addr = Layout::current().out_addr();
+
+    let reads = stable::read_count();
+    multiply_stable().unwrap();
+    let uncached_reads = stable::read_count() - reads;
+    let expected = read_stable_i32s(out_addr, d);
+
+    prepare_b_simd();
+    write_stable_i32s(out_addr, &vec![0; d]);
+    let reads = stable::read_count();
+    multiply_stable().unwrap();
+    let cached_reads = stable::read_count() - reads;
+
+    assert_eq!(read_stable_i32s(out_addr, d), expected);
+    assert!(
+        cached_reads < uncached_reads,
+        "{} reads with prepared b, {} without",
+        cached_reads,
+        uncached_reads
+    );
+
+    // Changing `b` drops the prepared copy.
+    set_b(vec![1; n]);
+    multiply_stable().unwrap();
+    multiply_heap_scaled(1).unwrap();
+    assert_eq!(read_stable_i32s(out_addr, d), get_output());
+}
+
+#[test]
+fn zero_dimensions_give_empty_or_zero_output() {
+    for (n, d) in [(0, 5), (5, 0), (0, 0)] {
+        init(n, d);
+        assert!(stable_size() >= 1);
+        let out_addr = Layout::current().out_addr();
+
+        multiply_heap().unwrap();
+        assert_eq!(
+            get_output(),
+            vec![0; d],
+            "multiply_heap, n = {}, d = {}",
+            n,
+            d
+        );
+        multiply_heap_scaled(3).unwrap();
+        assert_eq!(get_output(), vec![0; d]);
+        multiply_heap_requant(2).unwrap();
+        assert_eq!(get_output(), vec![0; d]);
+        assert_eq!(output_cursor(0), (vec![0; d], 0));
+
+        prepare_b_simd();
+        multiply_stable().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), vec![0; d]);
+        invalidate_prepared_b();
+        multiply_stable().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), vec![0; d]);
+        multiply_stable_old().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), vec![0; d]);
+
+        let argmax = (d > 0).then_some(0);
+        assert_eq!(multiply_heap_with_argmax(), (vec![0; d], argmax));
+        assert_eq!(multiply_argmax_dir(false), argmax);
+    }
+}
+
+#[test]
+fn multiply_stable_i64_does_not_overflow() {
+    let (n, d) = (7, 3);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| i32::MAX - x * 1000).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| (1 << 20) - x).collect();
+    let expected: Vec<i64> = a
+        .chunks(n)
+        .map(|row| row.iter().zip(&b).map(|(&a, &b)| a as i64 * b as i64).sum())
+        .collect();
+    assert!(expected.iter().any(|&val| i32::try_from(val).is_err()));
+    configure(d, a, b);
+
+    multiply_stable_i64().unwrap();
+
+    assert_eq!(get_output_i64(), expected);
+}
+
+#[test]
+fn simd_speedup_measures_both_paths() {
+    init(16, 8);
+    let (scalar, simd) = simd_speedup();
+    assert!(scalar > 0);
+    assert!(simd > 0);
+}
+
+#[test]
+fn fill_a_with_ones_sums_b() {
+    let (n, d) = (64, 3);
+    init(n, d);
+    fill_a(1);
+    let sum: i32 = DATA.with(|data| data.borrow().b.iter().sum());
+
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vec![sum; d]);
+    multiply_stable().unwrap();
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), d),
+        vec![sum; d]
+    );
+}
+
+#[test]
+fn fill_b_scales_row_sums_of_a() {
+    let (n, d) = (64, 3);
+    init(n, d);
+    fill_b(2);
+    let expected: Vec<i32> = DATA.with(|data| {
+        let data = data.borrow();
+        data.a
+            .chunks(n)
+            .map(|row| 2 * row.iter().sum::<i32>())
+            .collect()
+    });
+
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), expected);
+    multiply_stable().unwrap();
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), expected);
+}
+
+#[test]
+fn output_close_to_respects_tolerance() {
+    init_f32(3, 2);
+    set_a_f32(vec![0.5, 1.0, -2.0, 0.25, 0.0, 4.0]);
+    set_b_f32(vec![1.0, 2.0, 0.5]);
+    multiply_heap_f32().unwrap();
+    let out = get_output_f32();
+    assert_eq!(out, vec![1.5, 2.25]);
+
+    assert!(output_close_to(out.clone(), 0.0));
+    let nudged: Vec<f32> = out.iter().map(|x| x + 1e-3).collect();
+    assert!(output_close_to(nudged.clone(), 1e-2));
+    assert!(!output_close_to(nudged, 1e-4));
+    let one_off = vec![out[0], out[1] - 0.5];
+    assert!(!output_close_to(one_off, 0.1));
+}
+
+#[test]
+fn benchmark_n_reports_stable_average() {
+    init(16, 8);
+    let first = benchmark_n(1, 3);
+    let second = benchmark_n(2, 5);
+    assert!(first > 0);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn multiply_submatrix_matches_restricted_full_multiply() {
+    let (n, d) = (64, 6);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| x % 11 - 5).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| x % 7 - 3).collect();
+    configure(d, a.clone(), b.clone());
+    multiply_heap().unwrap();
+    let full = get_output();
+
+    // All columns of some rows: those rows match the full multiply, and the
+    // rest keep their previous values.
+    configure(d, a.clone(), b.clone());
+    multiply_submatrix(1, 4, 0, n as u64).unwrap();
+    let out = get_output();
+    assert_eq!(out[1..4], full[1..4]);
+    assert_eq!(out[..1], [0]);
+    assert_eq!(out[4..], [0, 0]);
+
+    // A block of columns.
+    multiply_submatrix(2, 6, 10, 30).unwrap();
+    let out = get_output();
+    for i in 2..6 {
+        let expected: i32 = (10..30).map(|j| a[i * n + j] * b[j]).sum();
+        assert_eq!(out[i], expected);
+    }
+    assert_eq!(out[..2], [0, full[1]]);
+
+    // Overflowing dot products wrap, as in the full multiply.
+    fill_b(i32::MAX / 3);
+    multiply_heap().unwrap();
+    let full = get_output();
+    set_output(vec![0; d]);
+    multiply_submatrix(0, d as u64, 0, n as u64).unwrap();
+    assert_eq!(get_output(), full);
+}
+
+#[test]
+#[should_panic(expected = "n = 4294967296, d = 4294967296 is too large")]
+fn import_state_rejects_overflowing_shapes() {
+    let mut state = export_state();
+    let huge = (1_u64 << 32).to_le_bytes();
+    state[8..16].copy_from_slice(&huge);
+    state[16..24].copy_from_slice(&huge);
+    import_state(state);
+}
+
+#[test]
+fn export_import_state_round_trips() {
+    let (n, d) = (64, 5);
+    configure(
+        d,
+        (0..(n * d) as i32).map(|x| x % 13 - 6).collect(),
+        (0..n as i32).map(|x| 4 - x % 9).collect(),
+    );
+    multiply_heap().unwrap();
+    let output = get_output();
+    let state = export_state();
+
+    init(8, 3);
+    import_state(state.clone());
+    assert_eq!(get_output(), output);
+    assert_eq!(export_state(), state);
+
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), output);
+    multiply_stable().unwrap();
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), output);
+}
+
+#[test]
+fn multiply_heap_threshold_matches_full_multiply() {
+    let (n, d) = (64, 8);
+    configure(
+        d,
+        (0..(n * d) as i32).map(|x| (x * 7) % 5).collect(),
+        (0..n as i32).map(|x| x % 3).collect(),
+    );
+    multiply_heap().unwrap();
+    let out = get_output();
+
+    for threshold in [-1, 0, out[0], out[3] - 1, 150, i32::MAX] {
+        let expected: Vec<bool> = out.iter().map(|&val| val > threshold).collect();
+        assert_eq!(
+            multiply_heap_threshold(threshold),
+            expected,
+            "threshold {}",
+            threshold
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "non-negative")]
+fn multiply_heap_threshold_rejects_negative_inputs() {
+    configure(1, vec![1, -1], vec![1, 1]);
+    multiply_heap_threshold(0);
+}
+
+#[test]
+fn output_histogram_counts_and_clamps() {
+    let out = vec![-5, 0, 1, 2, 3, 9, 10, 11, 100];
+    configure(out.len(), out.clone(), vec![1]);
+    multiply_heap_scaled(1).unwrap();
+    assert_eq!(get_output(), out);
+
+    // Bins of width 3: [0, 2], [3, 5], [6, 8] and [9, 11].
+    assert_eq!(output_histogram(4, 0, 11), vec![4, 1, 0, 4]);
+    assert_eq!(output_histogram(1, 0, 11), vec![9]);
+    assert_eq!(output_histogram(2, i32::MIN, i32::MAX), vec![1, 8]);
+}
+
+#[test]
+fn multiply_heap_padded_matches_zero_padded_b() {
+    let (n, d, b_len) = (64, 4, 23);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| x % 17 - 8).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| x % 5 - 1).collect();
+    let mut padded = b.clone();
+    padded[b_len..].fill(0);
+
+    configure(d, a.clone(), padded);
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    configure(d, a, b);
+    multiply_heap_padded(b_len as u64).unwrap();
+    assert_eq!(get_output(), expected);
+}
+
+#[test]
+fn layout_regions_are_disjoint_and_allocated() {
+    for (n, d) in [(0, 0), (0, 5), (5, 0), (6, 3), (64, 64)] {
+        let layout = Layout::new(n, d);
+        check_regions(&layout.regions(), layout.pages() * WASM_PAGE_SIZE);
+    }
+}
+
+#[test]
+// `check_regions` only checks in debug builds.
+#[cfg(debug_assertions)]
+#[should_panic(expected = "stable region b [8, 24) overlaps out [16, 32)")]
+fn check_regions_detects_overlap() {
+    let regions = [
+        Region::new("a", 0, 8),
+        Region::new("b", 8, 16),
+        Region::new("out", 16, 16),
+    ];
+    check_regions(&regions, WASM_PAGE_SIZE);
+}
+
+#[test]
+// `check_regions` only checks in debug builds.
+#[cfg(debug_assertions)]
+#[should_panic(expected = "exceeds the 65536 allocated bytes")]
+fn check_regions_detects_unallocated_region() {
+    check_regions(&[Region::new("a", 0, WASM_PAGE_SIZE + 4)], WASM_PAGE_SIZE);
+}
+
+#[test]
+fn multiply_pure_stable_reads_only_stable_memory() {
+    let (n, d) = (9, 4);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| 2 * x - 30).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| x % 4 - 1).collect();
+    let expected: Vec<i32> = a
+        .chunks(n)
+        .map(|row| row.iter().zip(&b).map(|(a, b)| a * b).sum())
+        .collect();
+
+    let layout = Layout::new(n as u64, d as u64);
+    stable_grow(1).unwrap();
+    write_stable_i32s(layout.a_addr(), &a);
+    write_stable_i32s(layout.b_addr(), &b);
+
+    multiply_pure_stable(n as u64, d as u64).unwrap();
+
+    assert_eq!(read_stable_i32s(layout.out_addr(), d), expected);
+    assert_eq!(Layout::current(), Layout::new(0, 0));
+}
+
+#[test]
+fn reseed_is_deterministic_per_seed() {
+    init(16, 4);
+    let snapshot = || DATA.with(|data| (data.borrow().a.clone(), data.borrow().b.clone()));
+
+    reseed(1);
+    let first = snapshot();
+    reseed(1);
+    assert_eq!(snapshot(), first);
+    assert_eq!(Layout::current(), Layout::new(16, 4));
+
+    reseed(2);
+    assert_ne!(snapshot(), first);
+
+    // The stable copy is refreshed too.
+    multiply_heap_scaled(1).unwrap();
+    multiply_stable().unwrap();
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), 4),
+        get_output()
+    );
+}
+
+#[test]
+fn multiply_count_survives_upgrade() {
+    init(8, 4);
+    assert_eq!(multiply_count(), 0);
+    multiply_heap_scaled(1).unwrap();
+    multiply_stable().unwrap();
+    multiply_stable_old().unwrap();
+    assert_eq!(multiply_count(), 3);
+    benchmark_n(1, 2);
+    assert_eq!(multiply_count(), 6);
+
+    let (a, b) = DATA.with(|data| (data.borrow().a.clone(), data.borrow().b.clone()));
+    pre_upgrade();
+    DATA.with(|data| data.borrow_mut().call_count = 0);
+    post_upgrade(8, 4);
+
+    assert_eq!(multiply_count(), 6);
+    DATA.with(|data| assert_eq!((&data.borrow().a, &data.borrow().b), (&a, &b)));
+    multiply_heap_scaled(1).unwrap();
+    assert_eq!(multiply_count(), 7);
+}
+
+#[test]
+fn upgrade_drops_named_matrices_runs_and_the_error_mode() {
+    init(8, 4);
+    save_matrix("layer".to_string());
+    init_rle(vec![(1, 32)]);
+    set_error_mode(ErrorMode::Result);
+    assert!(named_start().is_some());
+
+    pre_upgrade();
+    post_upgrade(8, 4);
+    assert!(named_start().is_none());
+    assert!(NAMED.with(|named| named.borrow().is_empty()));
+    assert_eq!(ERROR_MODE.with(Cell::get), ErrorMode::Trap);
+    assert_eq!(memory_stats().stable_used_bytes, Layout::current().size());
+    // The freed regions can be overwritten by a larger layout.
+    reconfigure(64, 16).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "upgraded with n = 4, d = 8")]
+fn post_upgrade_rejects_a_different_shape() {
+    init(8, 4);
+    pre_upgrade();
+    post_upgrade(4, 8);
+}
+
+#[test]
+fn multiply_stable_to_writes_at_offset() {
+    let (n, d) = (6, 5);
+    init(n, d);
+    multiply_stable().unwrap();
+    let expected = read_stable_i32s(Layout::current().out_addr(), d);
+
+    let offset = Layout::current().size() + 12;
+    multiply_stable_to(offset).unwrap();
+    assert_eq!(read_stable_i32s(offset, d), expected);
+}
+
+#[test]
+#[should_panic(expected = "exceeds the 65536 allocated bytes")]
+fn multiply_stable_to_rejects_unallocated_offset() {
+    init(6, 5);
+    multiply_stable_to(WASM_PAGE_SIZE - 8).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "overflows stable memory")]
+fn multiply_stable_to_rejects_overflowing_offset() {
+    init(6, 5);
+    multiply_stable_to(u64::MAX - 8).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "overlaps stable region b")]
+fn multiply_stable_to_rejects_overlapping_offsets() {
+    init(6, 5);
+    multiply_stable_to(Layout::current().b_addr() + 4).unwrap();
+}
+
+#[test]
+fn multiply_stable_strided_matches_contiguous_b() {
+    let (n, d) = (11, 6);
+    init(n, d);
+    set_bias(vec![5, -5, 0, 1, i32::MAX, -100]);
+    let b: Vec<i32> = (0..n as i32).map(|x| 3 * x - 7).collect();
+    set_b(b.clone());
+    multiply_stable().unwrap();
+    let expected = read_stable_i32s(Layout::current().out_addr(), d);
+
+    let b_addr = Layout::current().size();
+    for stride in [4, 12, 6] {
+        for (j, x) in b.iter().enumerate() {
+            stable_write(b_addr + j as u64 * stride, &x.to_le_bytes());
+        }
+        multiply_stable_strided(stride).unwrap();
+        assert_eq!(
+            read_stable_i32s(Layout::current().out_addr(), d),
+            expected,
+            "stride {}",
+            stride
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "the strided b would share stable memory with the named matrices")]
+fn multiply_stable_strided_refuses_while_matrices_are_saved() {
+    init(11, 6);
+    save_matrix("layer".to_string());
+    multiply_stable_strided(4).unwrap();
+}
+
+#[test]
+fn output_queries_read_the_stable_output_after_a_stable_multiply() {
+    let (n, d) = (8, 5);
+    init(n, d);
+    reseed(3);
+    // Leaves a stale heap output that no query should see.
+    set_output(vec![0; d]);
+    multiply_stable().unwrap();
+    let out = get_output();
+    assert_ne!(out, vec![0; d]);
+
+    assert!(!output_is_zero());
+    assert_eq!(diff_output(out.clone()), Vec::<u64>::new());
+    let csv: Vec<String> = out.iter().map(i32::to_string).collect();
+    assert_eq!(output_csv(), csv.join(","));
+    assert_eq!(output_histogram(1, i32::MIN, i32::MAX), vec![d as u64]);
+    assert_eq!(output_bottomk(d as u64).len(), d);
+    assert_eq!(output_bottomk(1)[0].1, *out.iter().min().unwrap());
+    let max_abs = *out.iter().max_by_key(|val| (**val as i64).abs()).unwrap();
+    assert_eq!(output_max_abs().1.abs(), max_abs.abs());
+    let state = export_state();
+    let exported: Vec<i32> = state[state.len() - d * 4..]
+        .chunks_exact(4)
+        .map(|chunk| i32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    assert_eq!(exported, out);
+    snapshot_output();
+    assert_eq!(diff_snapshot(), Vec::<u64>::new());
+}
+
+#[test]
+fn output_max_abs_keeps_the_sign() {
+    let out = vec![3, -7, 7, 5, -2];
+    configure(out.len(), out, vec![1]);
+    multiply_heap_scaled(1).unwrap();
+    assert_eq!(output_max_abs(), (1, -7));
+
+    let out = vec![i32::MAX, 0, i32::MIN];
+    configure(out.len(), out, vec![1]);
+    multiply_heap_scaled(1).unwrap();
+    assert_eq!(output_max_abs(), (2, i32::MIN));
+}
+
+#[test]
+fn error_mode_result_returns_dimension_errors() {
+    set_error_mode(ErrorMode::Result);
+    init(10, 3);
+    let err = multiply_stable_old().unwrap_err();
+    assert_eq!(err, MatMulError::UnsupportedLength { n: 10, group: 4 });
+    assert_eq!(err.to_string(), "n = 10 is not a multiple of 4");
+    assert_eq!(multiply_count(), 0);
+    assert_eq!(multiply_heap(), Ok(()));
+    assert_eq!(multiply_stable(), Ok(()));
+
+    DATA.with(|data| data.borrow_mut().a.pop());
+    let mismatch = Err(MatMulError::ShapeMismatch {
+        a_len: 29,
+        n: 10,
+        d: 3,
+    });
+    assert_eq!(multiply_heap(), mismatch);
+    assert_eq!(multiply_heap_scaled(2), mismatch);
+    assert_eq!(multiply_heap_requant(1), mismatch);
+    assert_eq!(multiply_ternary(), mismatch);
+    assert_eq!(multiply_rle(), mismatch);
+    assert_eq!(multiply_binary_b(vec![0; 2]), mismatch);
+    assert_eq!(multiply_heap_clamped_inputs(-1, 1), mismatch);
+    assert_eq!(multiply_heap_ema(1, 2), mismatch);
+    assert_eq!(multiply_submatrix(0, 3, 0, 10), mismatch);
+    assert_eq!(multiply_heap_padded(10), mismatch);
+    assert_eq!(multiply_transpose(), mismatch);
+
+    init_f32(4, 2);
+    DATA_F32.with(|data| data.borrow_mut().a.pop());
+    let mismatch = Err(MatMulError::ShapeMismatch {
+        a_len: 7,
+        n: 4,
+        d: 2,
+    });
+    assert_eq!(multiply_heap_f32(), mismatch);
+    assert_eq!(multiply_heap_f32_blocked(2), mismatch);
+    assert_eq!(multiply_heap_f32_ordered(), mismatch);
+    init_i64(4, 2);
+    DATA_I64.with(|data| data.borrow_mut().a.pop());
+    assert_eq!(multiply_heap_i64(), mismatch);
+
+    assert_eq!(
+        multiply_pure_stable(1 << 32, 1 << 32),
+        Err(MatMulError::ShapeTooLarge {
+            n: 1 << 32,
+            d: 1 << 32
+        })
+    );
+    assert_eq!(multiply_count(), 2);
+}
+
+#[test]
+#[should_panic(expected = "n = 10 is not a multiple of 4")]
+fn error_mode_trap_traps_on_dimension_errors() {
+    set_error_mode(ErrorMode::Trap);
+    init(10, 3);
+    let _ = multiply_stable_old();
+}
+
+#[test]
+fn multiply_and_check_compares_against_expected() {
+    let (n, d) = (64, 3);
+    init(n, d);
+    multiply_heap().unwrap();
+    let mut expected = get_output();
+    assert!(multiply_and_check(expected.clone()));
+
+    expected[1] += 1;
+    assert!(!multiply_and_check(expected));
+}
+
+#[test]
+fn multiply_heap_i64_accumulates_in_i128() {
+    init_i64(2, 3);
+    let big = 1 << 40;
+    // Each product is ±2^80, far beyond `i64`, but the first two rows cancel
+    // out to something that fits.
+    set_a_i64(vec![big, -big, big, 1 - big, big, big]);
+    set_b_i64(vec![big, big]);
+    multiply_heap_i64().unwrap();
+    assert_eq!(get_output_heap_i64(), vec![0, big, i64::MAX]);
+
+    set_b_i64(vec![-big, -big]);
+    multiply_heap_i64().unwrap();
+    assert_eq!(get_output_heap_i64(), vec![0, -big, i64::MIN]);
+}
+
+#[test]
+fn resync_stable_repairs_corrupted_stable_memory() {
+    let (n, d) = (64, 5);
+    init(n, d);
+    reseed(7);
+    set_bias(vec![1, -2, 3, i32::MAX, i32::MIN]);
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    corrupt_stable();
+    multiply_stable().unwrap();
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), d),
+        vec![0; d]
+    );
+
+    resync_stable();
+    multiply_stable().unwrap();
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), expected);
+}
+
+#[test]
+fn op_count_is_two_per_element_of_a() {
+    for (n, d) in [(0, 3), (1, 1), (64, 2), (7, 9)] {
+        init(n, d);
+        assert_eq!(op_count(), 2 * (n * d) as u64, "n = {}, d = {}", n, d);
+    }
+}
+
+#[test]
+fn load_all_bytes_loads_a_and_b() {
+    let (n, d) = (5, 3);
+    init(n, d);
+    let a: Vec<i32> = (0..(n * d) as i32).map(|x| 1000 - 77 * x).collect();
+    let b: Vec<i32> = (0..n as i32).map(|x| -x * x).collect();
+    let bytes: Vec<u8> = a.iter().chain(&b).flat_map(|x| x.to_le_bytes()).collect();
+
+    load_all_bytes(bytes);
+
+    DATA.with(|data| {
+        assert_eq!(data.borrow().a, a);
+        assert_eq!(data.borrow().b, b);
+    });
+    let layout = Layout::current();
+    assert_eq!(read_stable_i32s(layout.a_addr(), n * d), a);
+    assert_eq!(read_stable_i32s(layout.b_addr(), n), b);
+}
+
+#[test]
+fn mask_rows_zeroes_only_masked_outputs() {
+    let (n, d) = (64, 6);
+    init(n, d);
+    multiply_heap().unwrap();
+    let before = get_output();
+
+    mask_rows(vec![1, 4, 1]);
+    let masked = |out: Vec<i32>| -> Vec<i32> {
+        (0..d)
+            .map(|i| if i == 1 || i == 4 { 0 } else { out[i] })
+            .collect()
+    };
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), masked(before.clone()));
+    multiply_stable().unwrap();
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), d),
+        masked(before)
+    );
+}
+
+#[test]
+fn multiply_argmax_dir_breaks_ties_both_ways() {
+    // With `b` the first unit vector, each output is its row's first element.
+    let with_outputs = |out: &[i32]| {
+        let n = 64;
+        let mut a = vec![0; n * out.len()];
+        for (i, &val) in out.iter().enumerate() {
+            a[i * n] = val;
+        }
+        let mut b = vec![0; n];
+        b[0] = 1;
+        configure(out.len(), a, b);
+    };
+
+    with_outputs(&[3, 9, -1, 9, 2]);
+    assert_eq!(multiply_argmax_dir(false), Some(1));
+    assert_eq!(multiply_argmax_dir(true), Some(3));
+
+    with_outputs(&[4, 4, 4]);
+    assert_eq!(multiply_argmax_dir(false), Some(0));
+    assert_eq!(multiply_argmax_dir(true), Some(2));
+}
+
+#[test]
+fn multiply_stable_bias_relu_matches_separate_passes() {
+    // d = 7 has both whole `v128`s of rows and a tail.
+    let (n, d) = (64, 7);
+    init(n, d);
+    reseed(3);
+    set_bias(vec![-5000, 5000, 0, 12, -12, i32::MIN, 100_000]);
+
+    multiply_heap_bias_relu().unwrap();
+    let expected = get_output();
+    assert!(expected.iter().all(|&val| val >= 0));
+    assert!(expected.contains(&0));
+
+    multiply_stable_bias_relu().unwrap();
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), expected);
+}
+
+#[test]
+fn init_cost_matches_init() {
+    for (n, d) in [(0, 0), (100, 7), (1000, 70)] {
+        let pages = stable_size();
+        let cost = init_cost(n, d);
+        assert_eq!(stable_size(), pages);
+
+        init(n, d);
+        assert_eq!(stable_size(), cost.stable_pages.max(pages));
+        let heap_elems = DATA.with(|data| {
+            let data = data.borrow();
+            [
+                &data.a,
+                &data.b,
+                &data.out,
+                &data.out_back,
+                &data.bias,
+                &data.x,
+                &data.out_transpose,
+            ]
+            .iter()
+            .map(|vals| vals.len() as u64)
+            .sum::<u64>()
+        });
+        assert_eq!(cost.heap_bytes, heap_elems * 4);
+    }
+    assert_eq!(init_cost(1000, 70).stable_pages, 5);
+}
+
+#[test]
+fn max_shape_fills_max_stable_memory() {
+    let elems = max_shape();
+    assert!(elems * 4 + META_LEN <= MAX_STABLE_BYTES);
+    assert!((elems + 1) * 4 + META_LEN > MAX_STABLE_BYTES);
+    assert_eq!(MAX_STABLE_BYTES / WASM_PAGE_SIZE, 6_553_600);
+
+    // A square shape using about that many elements fits.
+    let n = (elems as f64).sqrt() as u64 - 4;
+    assert!(Layout::new(n, n).size() <= MAX_STABLE_BYTES);
+}
+
+#[test]
+fn multiply_stable_row_read_matches_per_v128_reads() {
+    for (n, d) in [(64, 5), (6, 3), (0, 2)] {
+        init(n, d);
+        reseed(11);
+        set_bias((0..d as i32).map(|i| i * 1000 - 2000).collect());
+        let out_addr = Layout::current().out_addr();
+        multiply_stable().unwrap();
+        let expected = read_stable_i32s(out_addr, d);
+        multiply_stable_row_read().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), expected, "n = {}", n);
+    }
+
+    init(64, 8);
+    let (per_v128, row_read) = row_read_speedup();
+    assert!(row_read < per_v128, "{} >= {}", row_read, per_v128);
+}
+
+#[test]
+fn output_has_nonfinite_detects_overflow() {
+    init_f32(2, 2);
+    set_a_f32(vec![1.0, 2.0, 3.0, 4.0]);
+    set_b_f32(vec![0.5, -1.0]);
+    multiply_heap_f32().unwrap();
+    assert!(!output_has_nonfinite());
+
+    set_a_f32(vec![1.0, 2.0, f32::MAX, f32::MAX]);
+    set_b_f32(vec![2.0, 2.0]);
+    multiply_heap_f32().unwrap();
+    assert_eq!(get_output_f32()[1], f32::INFINITY);
+    assert!(output_has_nonfinite());
+}
+
+#[test]
+fn a_row_norms_sq_sums_squares_per_row() {
+    configure(3, vec![1, -2, 3, 0, 0, 0, i32::MIN, 4, -5], vec![1; 3]);
+    assert_eq!(a_row_norms_sq(), vec![14, 0, (1 << 62) + 16 + 25]);
+}
+
+#[test]
+fn multiply_heap_ema_matches_reference() {
+    let (n, d) = (64, 4);
+    init(n, d);
+    reseed(5);
+    multiply_heap().unwrap();
+    let new = get_output();
+    let ema = |old: &[i32]| -> Vec<i32> {
+        new.iter()
+            .zip(old)
+            .map(|(&new, &old)| ((new as i64 + 3 * old as i64) / 4) as i32)
+            .collect()
+    };
+
+    // Reseeding resets the output to zero.
+    reseed(5);
+    multiply_heap_ema(1, 4).unwrap();
+    let once = ema(&[0; 4]);
+    assert_eq!(get_output(), once);
+    multiply_heap_ema(1, 4).unwrap();
+    assert_eq!(get_output(), ema(&once));
+}
+
+#[test]
+fn multiply_sparse_output_keeps_nonzero_outputs() {
+    let (n, d) = (64, 8);
+    init(n, d);
+    mask_rows(vec![0, 1, 2, 4, 5, 7]);
+
+    let sparse = multiply_sparse_output();
+    let dense = get_output();
+    assert_eq!(
+        sparse.iter().map(|&(i, _)| i).collect::<Vec<_>>(),
+        vec![3, 6]
+    );
+    let mut densified = vec![0; d];
+    for (i, val) in sparse {
+        densified[i as usize] = val;
+    }
+    assert_eq!(densified, dense);
+}
+
+#[test]
+fn dot_rows_matches_individual_dots() {
+    let (n, d) = (10, 6);
+    init(n, d);
+    reseed(2);
+    let rows = vec![5, 0, 3, 3];
+    let individual: Vec<i32> = rows.iter().map(|&row| dot(row)).collect();
+    assert_eq!(dot_rows(rows), individual);
+
+    multiply_stable().unwrap();
+    let out = read_stable_i32s(Layout::current().out_addr(), d);
+    assert_eq!(individual, vec![out[5], out[0], out[3], out[3]]);
+
+    // Like the multiplies, overflowing dot products wrap.
+    fill_b(i32::MAX / 3);
+    multiply_heap().unwrap();
+    assert_eq!(dot_rows((0..d as u32).collect()), get_output());
+}
+
+#[test]
+#[should_panic(expected = "row 6 is out of range for d = 6")]
+fn dot_rows_rejects_out_of_range_rows() {
+    init(10, 6);
+    dot_rows(vec![0, 6]);
+}
+
+#[test]
+fn reconfigure_to_a_smaller_shape_frees_stable_memory() {
+    reconfigure(256, 128).unwrap();
+    let big = memory_stats();
+    assert_eq!(big.stable_used_bytes, Layout::new(256, 128).size());
+    assert!(big.stable_used_bytes <= big.stable_allocated_bytes);
+
+    reconfigure(8, 4).unwrap();
+    let small = memory_stats();
+    assert_eq!(small.stable_used_bytes, Layout::new(8, 4).size());
+    assert!(small.stable_used_bytes < big.stable_used_bytes);
+    assert_eq!(small.stable_allocated_bytes, big.stable_allocated_bytes);
+}
+
+#[test]
+fn heap_and_stable_multiplies_agree_bit_for_bit() {
+    let mut rng = 0x5eed;
+    for _ in 0..24 {
+        let n = (splitmix64(&mut rng) % 200) as usize;
+        let d = 1 + (splitmix64(&mut rng) % 12) as usize;
+        init(n, d);
+        reseed(splitmix64(&mut rng));
+        if splitmix64(&mut rng).is_multiple_of(3) {
+            // Large enough that the dot products overflow `i32`.
+            fill_b(i32::MAX / 3);
+        }
+        let out_addr = Layout::current().out_addr();
+
+        multiply_heap().unwrap();
+        let heap = get_output();
+        multiply_stable().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), heap, "n = {}, d = {}", n, d);
+        prepare_b_simd();
+        multiply_stable().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), heap, "n = {}, d = {}", n, d);
+        multiply_stable_row_read().unwrap();
+        assert_eq!(read_stable_i32s(out_addr, d), heap, "n = {}, d = {}", n, d);
+    }
+}
+
+#[test]
+fn multiply_heap_clamped_inputs_matches_clamped_data() {
+    let (n, d) = (70, 5);
+    init(n, d);
+    reseed(9);
+    let (a, b) = DATA.with(|data| (data.borrow().a.clone(), data.borrow().b.clone()));
+    let (lo, hi) = (-40, 25);
+
+    multiply_heap_clamped_inputs(lo, hi).unwrap();
+    let clamped = get_output();
+    DATA.with(|data| {
+        assert_eq!(data.borrow().a, a);
+        assert_eq!(data.borrow().b, b);
+    });
+
+    let clamp = |v: &[i32]| v.iter().map(|&x| x.clamp(lo, hi)).collect();
+    configure(d, clamp(&a), clamp(&b));
+    multiply_heap().unwrap();
+    assert_eq!(clamped, get_output());
+}
+
+#[test]
+fn output_csv_formats_the_output() {
+    let out = vec![3, -14, 0, i32::MIN];
+    configure(out.len(), out, vec![1]);
+    multiply_heap().unwrap();
+    assert_eq!(output_csv(), "3,-14,0,-2147483648");
+
+    configure(0, vec![], vec![1]);
+    assert_eq!(output_csv(), "");
+}
+
+#[test]
+fn plan_matches_multiply_stable_reads() {
+    let (n, d) = (10, 3);
+    init(n, d);
+    let plan_of_run = || {
+        let expected = plan();
+        let reads = stable::read_count();
+        multiply_stable().unwrap();
+        (expected, stable::read_count() - reads)
+    };
+
+    let (uncached, reads) = plan_of_run();
+    assert_eq!(
+        uncached,
+        Plan {
+            element_bytes: 4,
+            host_reads: 3 * ((2 + 2) * 2 + 1),
+            simd_iterations: 3 * 2,
+            scalar_tail: 3 * 2,
+        }
+    );
+    assert_eq!(uncached.host_reads, reads);
+
+    prepare_b_simd();
+    let (cached, reads) = plan_of_run();
+    assert_eq!(cached.host_reads, 3 * (2 + 2 + 1));
+    assert_eq!(cached.host_reads, reads);
+}
+
+#[test]
+fn multiply_stable_applies_bias_like_multiply_heap() {
+    let (n, d) = (13, 6);
+    init(n, d);
+    reseed(4);
+    multiply_heap().unwrap();
+    let unbiased = get_output();
+
+    let bias = vec![7, -7, 0, i32::MAX, 1000, -1];
+    set_bias(bias.clone());
+    multiply_heap().unwrap();
+    let heap = get_output();
+    let expected: Vec<i32> = unbiased
+        .iter()
+        .zip(&bias)
+        .map(|(out, bias)| out.wrapping_add(*bias))
+        .collect();
+    assert_eq!(heap, expected);
+
+    multiply_stable().unwrap();
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), d), heap);
+    assert_eq!(read_stable_i32s(Layout::current().bias_addr(), d), bias);
+
+    // The bias is kept across upgrades, as it lives in stable memory.
+    pre_upgrade();
+    post_upgrade(n, d);
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), heap);
+}
+
+#[test]
+fn every_multiply_of_a_b_adds_the_bias() {
+    let (n, d) = (13, 6);
+    init(n, d);
+    reseed(5);
+    multiply_heap().unwrap();
+    let unbiased = get_output();
+    let bias = vec![7, -7, 0, i32::MAX, 1000, i32::MIN];
+    set_bias(bias.clone());
+    multiply_heap().unwrap();
+    let heap = get_output();
+
+    let runs: [(&str, &dyn Fn() -> Vec<i32>); 10] = [
+        ("padded", &|| {
+            multiply_heap_padded(n as u64).unwrap();
+            get_output()
+        }),
+        ("submatrix", &|| {
+            multiply_submatrix(0, d as u64, 0, n as u64).unwrap();
+            get_output()
+        }),
+        ("clamped_inputs", &|| {
+            multiply_heap_clamped_inputs(i32::MIN, i32::MAX).unwrap();
+            get_output()
+        }),
+        ("mode", &|| multiply_heap_mode(OverflowMode::Wrap)),
+        ("scaled", &|| {
+            multiply_heap_scaled(1).unwrap();
+            get_output()
+        }),
+        ("requant", &|| {
+            multiply_heap_requant(0).unwrap();
+            get_output()
+        }),
+        ("rowwise", &|| {
+            let b = DATA.with(|data| data.borrow().b.clone());
+            multiply_rowwise(vec![b; d])
+        }),
+        ("both", &|| multiply_both(vec![0; d]).0),
+        ("pure_stable", &|| {
+            multiply_pure_stable(n as u64, d as u64).unwrap();
+            read_stable_i32s(Layout::current().out_addr(), d)
+        }),
+        ("stable_i64", &|| {
+            multiply_stable_i64().unwrap();
+            get_output_i64().iter().map(|&val| val as i32).collect()
+        }),
+    ];
+    for (name, run) in runs {
+        set_output(vec![0; d]);
+        assert_eq!(run(), heap, "{}", name);
+    }
+
+    // Each output of `multiply_onehot` is an element of `A` plus the bias.
+    let a0: Vec<i32> = DATA.with(|data| data.borrow().a.iter().step_by(n).copied().collect());
+    let expected: Vec<i32> = a0
+        .iter()
+        .zip(&bias)
+        .map(|(a, b)| a.wrapping_add(*b))
+        .collect();
+    assert_eq!(multiply_onehot(0), expected);
+    // And `multiply_stable_i64` doesn't wrap.
+    let wide: Vec<i64> = unbiased
+        .iter()
+        .zip(&bias)
+        .map(|(&out, &bias)| out as i64 + bias as i64)
+        .collect();
+    multiply_stable_i64().unwrap();
+    assert_eq!(get_output_i64(), wide);
+}
+
+#[test]
+fn check_shape_rejects_overflowing_shapes() {
+    assert_eq!(check_shape(64, 32), Ok(()));
+    assert_eq!(
+        check_shape(usize::MAX / 2, 3),
+        Err(MatMulError::ShapeTooLarge {
+            n: usize::MAX as u64 / 2,
+            d: 3
+        })
+    );
+    // `n * d` fits, but not its layout in bytes.
+    assert!(check_shape(1 << 31, 1 << 31).is_err());
+    assert!(check_shape(1 << 20, 1 << 20).is_err());
+
+    set_error_mode(ErrorMode::Result);
+    init(4, 2);
+    assert_eq!(
+        reconfigure(1 << 40, 1 << 40),
+        Err(MatMulError::ShapeTooLarge {
+            n: 1 << 40,
+            d: 1 << 40
+        })
+    );
+    assert_eq!(Layout::current(), Layout::new(4, 2));
+}
+
+#[test]
+fn multiply_onehot_matches_full_multiply() {
+    let (n, d) = (9, 5);
+    init(n, d);
+    reseed(6);
+    for k in [0, 4, n - 1] {
+        let column = multiply_onehot(k as u64);
+        let mut b = vec![0; n];
+        b[k] = 1;
+        set_b(b);
+        multiply_heap().unwrap();
+        assert_eq!(column, get_output(), "k = {}", k);
+    }
+}
+
+#[test]
+fn set_verbose_logs_without_changing_output() {
+    let (n, d) = (20, 4);
+    init(n, d);
+    let log_lines = || LOG_LINES.with(Cell::get);
+    let run = || {
+        multiply_heap().unwrap();
+        multiply_stable().unwrap();
+        (
+            get_output(),
+            read_stable_i32s(Layout::current().out_addr(), d),
+        )
+    };
+
+    let quiet = run();
+    assert_eq!(log_lines(), 0);
+
+    set_verbose(true);
+    assert_eq!(run(), quiet);
+    assert_eq!(log_lines(), 2);
+
+    set_verbose(false);
+    assert_eq!(run(), quiet);
+    assert_eq!(log_lines(), 2);
+}
+
+#[test]
+fn multiply_transpose_matches_reference() {
+    let (n, d) = (4, 3);
+    let a = vec![1, 2, 3, 4, -5, 6, 0, 1, 2, -2, 2, -2];
+    configure(d, a.clone(), vec![0; n]);
+    set_x(vec![3, -1, 10]);
+    multiply_transpose().unwrap();
+
+    let mut expected = vec![0; n];
+    for j in 0..n {
+        for i in 0..d {
+            expected[j] += a[i * n + j] * [3, -1, 10][i];
+        }
+    }
+    assert_eq!(get_output_transpose(), expected);
+    assert_eq!(expected, vec![28, -20, 29, -9]);
+
+    init(0, 3);
+    multiply_transpose().unwrap();
+    assert_eq!(get_output_transpose(), Vec::<i32>::new());
+}
+
+#[test]
+fn multiply_both_matches_separate_multiplies() {
+    let (n, d) = (37, 9);
+    init(n, d);
+    reseed(8);
+    let x: Vec<i32> = (0..d as i32).map(|i| 5 - 2 * i).collect();
+
+    multiply_heap().unwrap();
+    set_x(x.clone());
+    multiply_transpose().unwrap();
+    assert_eq!(multiply_both(x), (get_output(), get_output_transpose()));
+}
+
+#[test]
+fn layout_pages_cover_the_layout_tightly() {
+    // n = 5456, d = 2 takes exactly one page.
+    assert_eq!(Layout::new(5456, 2).size(), WASM_PAGE_SIZE);
+    for (n, d) in [(0, 0), (1, 1), (64, 64), (100, 163), (5456, 2), (5457, 2)] {
+        let layout = Layout::new(n, d);
+        let allocated = layout.pages() * WASM_PAGE_SIZE;
+        assert!(allocated >= layout.size(), "n = {}, d = {}", n, d);
+        assert!(
+            allocated - layout.size() < WASM_PAGE_SIZE,
+            "n = {}, d = {} wastes a page",
+            n,
+            d
+        );
+    }
+    assert_eq!(Layout::new(0, 0).pages(), 1);
+    assert_eq!(Layout::new(5456, 2).pages(), 1);
+
+    // Stable memory only grows, so check increasingly large shapes.
+    for (n, d) in [(8, 8), (5456, 2), (100, 200)] {
+        init(n as usize, d as usize);
+        assert_eq!(stable_size(), Layout::new(n, d).pages());
+        assert!(stable_size() * WASM_PAGE_SIZE >= Layout::new(n, d).out_addr() + d * 4);
+    }
+}
+
+#[test]
+fn diff_output_finds_differing_indices() {
+    init(16, 10);
+    multiply_heap().unwrap();
+    let mut other = get_output();
+    assert_eq!(diff_output(other.clone()), Vec::<u64>::new());
+
+    other[0] += 1;
+    other[7] = -other[7] - 1;
+    other[9] = i32::MIN;
+    assert_eq!(diff_output(other), vec![0, 7, 9]);
+}
+
+#[test]
+fn shift_a_round_trips_within_precision() {
+    let a = vec![5, -3, 0, 1 << 20, -(1 << 20), 7];
+    configure(2, a.clone(), vec![1; 3]);
+    let stored = || DATA.with(|data| data.borrow().a.clone());
+
+    shift_a(8);
+    assert_eq!(stored(), a.iter().map(|x| x * 256).collect::<Vec<_>>());
+    shift_a(-8);
+    assert_eq!(stored(), a);
+    assert_eq!(read_stable_i32s(Layout::current().a_addr(), a.len()), a);
+
+    // Right shifts drop the low bits, rounding down.
+    shift_a(-2);
+    shift_a(2);
+    assert_eq!(stored(), vec![4, -4, 0, 1 << 20, -(1 << 20), 4]);
+}
+
+#[test]
+#[should_panic(expected = "1048576 << 11 overflows i32")]
+fn shift_a_rejects_overflow() {
+    configure(1, vec![1, 1 << 20], vec![1; 2]);
+    shift_a(11);
+}
+
+#[test]
+fn output_bottomk_matches_sorted_reference() {
+    let out = vec![5, -2, 9, -2, 0, 14, -30, 5];
+    configure(out.len(), out.clone(), vec![1]);
+    multiply_heap().unwrap();
+    let mut sorted: Vec<(u64, i32)> = out
+        .iter()
+        .enumerate()
+        .map(|(i, &val)| (i as u64, val))
+        .collect();
+    sorted.sort_by_key(|&(i, val)| (val, i));
+
+    assert_eq!(output_bottomk(3), vec![(6, -30), (1, -2), (3, -2)]);
+    for k in 0..=out.len() {
+        assert_eq!(output_bottomk(k as u64), sorted[..k], "k = {}", k);
+    }
+    assert_eq!(output_bottomk(100), sorted);
+}
+
+#[test]
+fn multiply_heap_f32_blocked_reduces_rounding_error() {
+    let n = 1 << 16;
+    init_f32(n, 1);
+    let a: Vec<f32> = (0..n).map(|i| 0.1 + (i % 7) as f32 * 1e-3).collect();
+    set_a_f32(a.clone());
+    set_b_f32(vec![1.0; n]);
+    let exact: f64 = a.iter().map(|&a| a as f64).sum();
+    let error = || (get_output_f32()[0] as f64 - exact).abs();
+
+    multiply_heap_f32().unwrap();
+    let naive = error();
+    multiply_heap_f32_blocked(64).unwrap();
+    let blocked = error();
+    assert!(
+        blocked < naive / 10.0,
+        "blocked error {} vs naive {}",
+        blocked,
+        naive
+    );
+
+    // A block as long as the row is the naive sum.
+    multiply_heap_f32_blocked(n as u32).unwrap();
+    assert_eq!(error(), naive);
+}
+
+#[test]
+fn multiply_heap_f32_ordered_sums_left_to_right() {
+    let (n, d) = (1000, 3);
+    init_f32(n, d);
+    let a: Vec<f32> = (0..n * d)
+        .map(|i| ((i * 37) % 101) as f32 * 0.013 - 0.6)
+        .collect();
+    let b: Vec<f32> = (0..n).map(|i| 1.0 / (i + 1) as f32).collect();
+    set_a_f32(a.clone());
+    set_b_f32(b.clone());
+    let expected: Vec<u32> = a
+        .chunks(n)
+        .map(|row| {
+            let mut acc = 0.0f32;
+            for (a, b) in row.iter().zip(&b) {
+                acc += a * b;
+            }
+            acc.to_bits()
+        })
+        .collect();
+    let bits = || -> Vec<u32> { get_output_f32().iter().map(|x| x.to_bits()).collect() };
+
+    multiply_heap_f32_ordered().unwrap();
+    assert_eq!(bits(), expected);
+    // Other kernels in between don't change what it computes.
+    multiply_heap_f32_blocked(16).unwrap();
+    multiply_heap_f32_ordered().unwrap();
+    assert_eq!(bits(), expected);
+}
+
+#[test]
+fn set_output_is_seen_by_every_read_path() {
+    init(8, 5);
+    let out = vec![4, -9, 0, 9, 2];
+    set_output(out.clone());
+
+    assert_eq!(get_output(), out);
+    assert_eq!(read_stable_i32s(Layout::current().out_addr(), 5), out);
+    assert_eq!(output_cursor(0), (out.clone(), 0));
+    assert_eq!(output_csv(), "4,-9,0,9,2");
+    assert_eq!(output_max_abs(), (1, -9));
+    assert_eq!(output_bottomk(2), vec![(1, -9), (2, 0)]);
+    assert!(!output_is_zero());
+    assert_eq!(diff_output(out), Vec::<u64>::new());
+}
+
+#[test]
+fn multiply_heap_nnz_counts_nonzero_products() {
+    let (n, d) = (12, 4);
+    init(n, d);
+    reseed(10);
+    mask_rows(vec![2]);
+    let mut b = DATA.with(|data| data.borrow().b.clone());
+    b[3] = 0;
+    set_b(b.clone());
+    let a = DATA.with(|data| data.borrow().a.clone());
+    let expected_nnz = a
+        .chunks(n)
+        .flat_map(|row| row.iter().zip(&b))
+        .filter(|(&a, &b)| a != 0 && b != 0)
+        .count() as u64;
+    assert!(expected_nnz <= ((d - 1) * (n - 1)) as u64);
+
+    let (out, nnz) = multiply_heap_nnz();
+    assert_eq!(nnz, expected_nnz);
+    multiply_heap().unwrap();
+    assert_eq!(out, get_output());
+}
+
+#[test]
+fn describe_reports_the_configured_matrix() {
+    assert!(!describe().initialized);
+    init(12, 3);
+    assert_eq!(
+        describe(),
+        Description {
+            n: 12,
+            d: 3,
+            dtype: DType::I32,
+            initialized: true,
+            storage_order: StorageOrder::RowMajor,
+        }
+    );
+    reconfigure(0, 0).unwrap();
+    assert_eq!((describe().n, describe().d), (0, 0));
+    assert!(describe().initialized);
+}
+
+#[test]
+fn bench_stable_reads_scales_with_a() {
+    init(16, 4);
+    let small = bench_stable_reads();
+    assert!(small > 0);
+    init(16, 16);
+    let large = bench_stable_reads();
+    assert!(large > 3 * small, "{} vs {}", large, small);
+    init(6, 3);
+    assert!(bench_stable_reads() > 0);
+}
+
+#[test]
+fn multiply_binary_b_matches_zero_one_b() {
+    let (n, d) = (21, 5);
+    init(n, d);
+    reseed(12);
+    let bits = vec![0b1010_0110, 0b0000_0001, 0b1_0000];
+    let b: Vec<i32> = (0..n)
+        .map(|j| (bits[j / 8] >> (j % 8) & 1) as i32)
+        .collect();
+    assert_eq!(b.iter().sum::<i32>(), 6);
+
+    multiply_binary_b(bits).unwrap();
+    let binary = get_output();
+    set_b(b);
+    multiply_heap().unwrap();
+    assert_eq!(binary, get_output());
+}
+
+#[test]
+fn named_matrices_reuse_freed_stable_regions() {
+    init(8, 4);
+    save_matrix("first".to_string());
+    save_matrix("second".to_string());
+    let addr = |name: &str| NAMED.with(|named| named.borrow()[name].addr);
+    let (first, second) = (addr("first"), addr("second"));
+    assert_eq!(first, Layout::current().size());
+    let len = (8 * 4 + 8 + 4) * 4;
+    assert_eq!(memory_stats().stable_used_bytes, second + len);
+    let a = DATA.with(|data| data.borrow().a.clone());
+    assert_eq!(read_stable_i32s(second, a.len()), a);
+
+    // Overwriting or dropping and saving again reuses the region.
+    save_matrix("first".to_string());
+    assert_eq!(addr("first"), first);
+    drop_matrix("first".to_string());
+    save_matrix("third".to_string());
+    assert_eq!(addr("third"), first);
+    let end = NAMED_ALLOC.with(|alloc| alloc.borrow().end());
+    for _ in 0..10 {
+        save_matrix("third".to_string());
+    }
+    assert_eq!(NAMED_ALLOC.with(|alloc| alloc.borrow().end()), end);
+}
+
+#[test]
+#[should_panic(expected = "would overwrite the named matrices")]
+fn configure_rejects_overlapping_named_matrices() {
+    init(8, 4);
+    save_matrix("saved".to_string());
+    init(16, 4);
+}
+
+#[test]
+fn multiply_heap_quantize_out_dequantizes_to_the_output() {
+    init(16, 6);
+    set_b((0..16).map(|i| i * i - 100).collect());
+    let (quantized, scale) = multiply_heap_quantize_out();
+    let out = get_output();
+    let max_abs = out.iter().map(|val| val.abs()).max().unwrap();
+    assert_eq!(scale, max_abs as f32 / 127.0);
+    assert_eq!(quantized.len(), out.len());
+    for (&q, &val) in quantized.iter().zip(&out) {
+        let error = (q as f32 * scale - val as f32).abs();
+        assert!(error <= scale / 2.0 + 1e-3 * scale, "{} vs {}", q, val);
+    }
+    assert!(quantized.iter().any(|q| q.abs() == 127));
+
+    fill_a(0);
+    assert_eq!(multiply_heap_quantize_out(), (vec![0; 6], 1.0));
+}
+
+#[test]
+fn check_overflow_risk_is_tight() {
+    // 4 * 2^14 * 2^15 is exactly 2^31, one past `i32::MAX`.
+    init(4, 2);
+    fill_a(1 << 14);
+    fill_b(1 << 15);
+    assert!(check_overflow_risk());
+    fill_b((1 << 15) - 1);
+    assert!(!check_overflow_risk());
+    fill_a(-(1 << 14));
+    assert!(!check_overflow_risk());
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vec![-(4 << 14) * ((1 << 15) - 1); 2]);
+
+    // The bias counts toward the worst case too.
+    set_bias(vec![i32::MAX - (4 << 14) * ((1 << 15) - 1), 0]);
+    assert!(!check_overflow_risk());
+    set_bias(vec![0, i32::MAX - (4 << 14) * ((1 << 15) - 1) + 1]);
+    assert!(check_overflow_risk());
+}
+
+#[test]
+fn multiply_i8_f32_matches_dequantized_reference() {
+    let (n, d) = (50, 4);
+    let a: Vec<i8> = (0..n * d).map(|i| ((i * 31) % 255) as i8).collect();
+    let b: Vec<f32> = (0..n).map(|j| (j as f32 * 0.37).sin()).collect();
+    let scale = 0.02;
+    init_i8_f32(a.clone(), b.clone(), scale);
+
+    // Dequantize all of `A` up front, then do a plain float multiply.
+    let dequantized: Vec<f32> = a.iter().map(|&a| a as f32 * scale).collect();
+    init_f32(n, d);
+    set_a_f32(dequantized);
+    set_b_f32(b);
+    multiply_heap_f32().unwrap();
+    let expected = get_output_f32();
+
+    let out = multiply_i8_f32();
+    assert_eq!(out.len(), d);
+    for (out, expected) in out.iter().zip(&expected) {
+        assert!((out - expected).abs() < 1e-4, "{} vs {}", out, expected);
+    }
+}
+
+#[test]
+fn recommended_path_prefers_the_heap_for_small_shapes() {
+    init(64, 8);
+    assert_eq!(recommended_path(), "heap");
+
+    // 2^14 * 2^14 `i32`s is exactly 1 GiB.
+    assert_eq!(recommend_path(1 << 14, 1 << 14, || panic!()), "heap");
+    assert_eq!(recommend_path(1 << 14, (1 << 14) + 1, || false), "stable");
+    assert_eq!(recommend_path(1 << 20, 1 << 20, || true), "stable_i16");
+}
+
+#[test]
+fn multiply_stable_saturating_clamps_and_counts() {
+    init(5, 4);
+    // Rows of the ramp `a` have first and last elements 4 apart, so each
+    // output is `-4 << 29`, exactly `i32::MIN`; the bias tips one over.
+    set_b(vec![1 << 29, 0, 0, 0, -(1 << 29)]);
+    set_bias(vec![0, -1, 1, 5]);
+    assert_eq!(multiply_stable_saturating(), 1);
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), 4),
+        vec![i32::MIN, i32::MIN, i32::MIN + 1, i32::MIN + 5]
+    );
+
+    set_b(vec![1 << 30, 0, 0, 0, 1 << 30]);
+    set_bias(vec![0; 4]);
+    // Row 0 sums to `4 << 30`, and the others further out.
+    assert_eq!(multiply_stable_saturating(), 4);
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), 4),
+        vec![i32::MAX; 4]
+    );
+}
+
+#[test]
+fn power_iterate_finds_the_dominant_eigenvector() {
+    // Symmetric, with eigenvalues 4, 3 and 1 and dominant eigenvector
+    // (1, 1, 1) / √3.
+    configure(3, vec![3, 1, 0, 1, 2, 1, 0, 1, 3], vec![1, 0, 0]);
+    let v = power_iterate(30);
+    assert_eq!(get_output(), v);
+    let norm = v.iter().map(|&x| (x as f64).powi(2)).sum::<f64>().sqrt();
+    let cos = v.iter().map(|&x| x as f64).sum::<f64>() / (norm * 3f64.sqrt());
+    assert!(cos > 0.9999, "{:?} has cosine {}", v, cos);
+    assert_eq!(v.iter().map(|x| x.abs()).max(), Some(1 << 15));
+
+    assert_eq!(power_iterate(0), vec![1, 0, 0]);
+}
+
+#[test]
+#[should_panic(expected = "power iteration needs a square A, got 2 × 3")]
+fn power_iterate_rejects_non_square() {
+    init(3, 2);
+    power_iterate(1);
+}
+
+#[test]
+fn diff_snapshot_finds_outputs_changed_since_the_snapshot() {
+    init(8, 6);
+    // Rows 2 and 4 have zeros where `b` will change.
+    DATA.with(|data| {
+        let mut data = data.borrow_mut();
+        data.a[2 * 8 + 3] = 0;
+        data.a[4 * 8 + 3] = 0;
+    });
+    multiply_heap().unwrap();
+    snapshot_output();
+    assert_eq!(diff_snapshot(), Vec::<u64>::new());
+    multiply_heap().unwrap();
+    assert_eq!(diff_snapshot(), Vec::<u64>::new());
+
+    let mut b: Vec<i32> = (0..8).collect();
+    b[3] = 100;
+    set_b(b);
+    multiply_heap().unwrap();
+    assert_eq!(diff_snapshot(), vec![0, 1, 3, 5]);
+
+    // Reshaping clears the snapshot.
+    init(8, 3);
+    assert_eq!(diff_snapshot(), Vec::<u64>::new());
+}
+
+#[test]
+fn set_simd_threshold_switches_to_the_scalar_loop() {
+    let (n, d) = (18, 5);
+    init(n, d);
+    reseed(3);
+    let out_addr = Layout::current().out_addr();
+    let run = || {
+        let start = instruction_counter();
+        multiply_stable().unwrap();
+        (read_stable_i32s(out_addr, d), instruction_counter() - start)
+    };
+
+    let (simd, simd_instructions) = run();
+    set_simd_threshold(n + 1);
+    assert_eq!(plan().simd_iterations, 0);
+    assert_eq!(plan().scalar_tail, (n * d) as u64);
+    // The scalar loop makes a host call per element, so it costs more.
+    let (scalar, scalar_instructions) = run();
+    assert_eq!(scalar, simd);
+    assert!(scalar_instructions > simd_instructions);
+
+    // Only `n` below the threshold use the scalar loop.
+    set_simd_threshold(n);
+    prepare_b_simd();
+    assert_eq!(run().0, simd);
+    assert!(plan().simd_iterations > 0);
+}
+
+#[test]
+fn pad_to_simd_width_keeps_outputs() {
+    for n in [0, 4, 13, 14, 15] {
+        let d = 3;
+        init(n, d);
+        reseed(n as u64);
+        set_bias(vec![5, -6, 7]);
+        multiply_heap().unwrap();
+        let expected = get_output();
+
+        pad_to_simd_width();
+        let padded = n.next_multiple_of(4);
+        assert_eq!(Layout::current(), Layout::new(padded as u64, d as u64));
+        assert_eq!(plan().scalar_tail, 0);
+        multiply_heap().unwrap();
+        assert_eq!(get_output(), expected, "n = {}", n);
+        multiply_stable().unwrap();
+        assert_eq!(
+            read_stable_i32s(Layout::current().out_addr(), d),
+            expected,
+            "n = {}",
+            n
+        );
+    }
+}
+
+#[test]
+fn multiply_active_runs_each_buffer_independently() {
+    init(3, 2);
+    init_f32(3, 2);
+    set_b_f32(vec![0.5, 0.25, -1.0]);
+    init_i64(2, 1);
+    assert_eq!(active_dtype(), DType::I32);
+
+    multiply_active().unwrap();
+    assert_eq!(get_output(), vec![5, 14]);
+    assert_eq!(get_output_f32(), vec![0.0; 2]);
+
+    set_active_dtype(DType::F32);
+    multiply_active().unwrap();
+    assert_eq!(get_output_f32(), vec![-1.75, -2.5]);
+    assert_eq!(get_output_heap_i64(), vec![0]);
+
+    set_active_dtype(DType::I64);
+    multiply_active().unwrap();
+    assert_eq!(get_output_heap_i64(), vec![1]);
+    // The other outputs are as they were.
+    assert_eq!(get_output(), vec![5, 14]);
+    assert_eq!(get_output_f32(), vec![-1.75, -2.5]);
+}
+
+#[test]
+fn multiply_rowwise_dots_each_row_with_its_vector() {
+    let (n, d) = (7, 4);
+    init(n, d);
+    reseed(9);
+    let vectors: Vec<Vec<i32>> = (0..d as i32)
+        .map(|i| (0..n as i32).map(|j| (i + 1) * j - 3).collect())
+        .collect();
+    let out = multiply_rowwise(vectors.clone());
+
+    // Each output is what `dot` gives for that row with `b` set to its vector.
+    for (i, (vector, out)) in vectors.into_iter().zip(out).enumerate() {
+        set_b(vector);
+        assert_eq!(out, dot(i as u32), "row {}", i);
+    }
+}
+
+#[test]
+#[should_panic(expected = "vector 1 must have length 7, got 6")]
+fn multiply_rowwise_rejects_short_vectors() {
+    init(7, 2);
+    multiply_rowwise(vec![vec![0; 7], vec![0; 6]]);
+}
+
+#[test]
+fn multiply_stable_bounded_resumes_where_it_stopped() {
+    let (n, d) = (12, 7);
+    init(n, d);
+    reseed(4);
+    set_bias((0..d as i32).collect());
+    multiply_stable().unwrap();
+    let out_addr = Layout::current().out_addr();
+    let expected = read_stable_i32s(out_addr, d);
+    write_stable_i32s(out_addr, &vec![0; d]);
+
+    // Three `v128`s each of `A` and `b`, the bias and the output write.
+    let row_cost = 6 * (100 + 16) + 2 * (100 + 4);
+    // Each call starts a new message with a fresh counter, and fits 3 rows.
+    let mut done = Vec::new();
+    while done.last() != Some(&(d as u64)) {
+        stable::reset_instruction_counter();
+        done.push(multiply_stable_bounded(3 * row_cost + row_cost / 2));
+        assert!(stable::instruction_counter() <= 3 * row_cost + row_cost / 2);
+    }
+    assert_eq!(done, vec![3, 6, 7]);
+    assert_eq!(read_stable_i32s(out_addr, d), expected);
+
+    // A budget too small for any row still makes progress.
+    assert_eq!(multiply_stable_bounded(0), 1);
+    init(n, d);
+    assert_eq!(multiply_stable_bounded(u64::MAX), d as u64);
+}
+
+#[test]
+fn multiply_stable_bounded_restarts_when_b_changes() {
+    let (n, d) = (12, 7);
+    init(n, d);
+    reseed(4);
+    assert_eq!(multiply_stable_bounded(0), 1);
+    assert_eq!(multiply_stable_bounded(0), 2);
+
+    set_b((0..n as i32).map(|j| 5 - j).collect());
+    multiply_stable().unwrap();
+    let out_addr = Layout::current().out_addr();
+    let expected = read_stable_i32s(out_addr, d);
+    write_stable_i32s(out_addr, &vec![0; d]);
+    // Starts over from row 0, rather than finishing the old rows off.
+    assert_eq!(multiply_stable_bounded(0), 1);
+    assert_eq!(multiply_stable_bounded(u64::MAX), d as u64);
+    assert_eq!(read_stable_i32s(out_addr, d), expected);
+}
+
+#[test]
+fn frobenius_dot_sums_elementwise_products() {
+    configure(2, vec![1, -2, 3, 4, 0, -6], vec![0; 3]);
+    let other = vec![7, 8, -9, 10, 11, 12];
+    assert_eq!(frobenius_dot(other), 7 - 16 - 27 + 40 - 72);
+
+    // Sums beyond `i32` are kept.
+    configure(1, vec![i32::MAX; 2], vec![0; 2]);
+    assert_eq!(
+        frobenius_dot(vec![i32::MAX; 2]),
+        2 * (i32::MAX as i64).pow(2)
+    );
+}
+
+#[test]
+#[should_panic(expected = "other must have length 6, got 5")]
+fn frobenius_dot_rejects_wrong_length() {
+    init(3, 2);
+    frobenius_dot(vec![0; 5]);
+}
+
+#[test]
+fn init_tiled_repeats_the_tile() {
+    init_tiled(5, 3, vec![1, -2, 3]);
+    let a = DATA.with(|data| data.borrow().a.clone());
+    assert_eq!(a, vec![1, -2, 3, 1, -2, 3, 1, -2, 3, 1, -2, 3, 1, -2, 3]);
+    assert_eq!(read_stable_i32s(Layout::current().a_addr(), 15), a);
+
+    // With `b` = [0, 1, 2, 3, 4].
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vec![-1, 10, 11]);
+    multiply_stable().unwrap();
+    assert_eq!(
+        read_stable_i32s(Layout::current().out_addr(), 3),
+        vec![-1, 10, 11]
+    );
+}
+
+#[test]
+#[should_panic(expected = "tile must not be empty")]
+fn init_tiled_rejects_empty_tile() {
+    init_tiled(4, 4, Vec::new());
+}
+
+#[test]
+fn measure_read_bandwidth_reads_all_of_a() {
+    for (n, d) in [(16, 4), (6, 3), (0, 2)] {
+        init(n, d);
+        let reads = stable::read_count();
+        let (bytes_read, instructions) = measure_read_bandwidth();
+        assert_eq!(bytes_read, (n * d * 4) as u64);
+        // The emulated counter charges a host call overhead plus the bytes.
+        let calls = stable::read_count() - reads;
+        assert_eq!(instructions, calls * 100 + bytes_read);
+    }
+}
+
+#[test]
+fn multiply_int8_subtracts_zero_points() {
+    let (n, d) = (40, 3);
+    let a: Vec<i8> = (0..n * d).map(|i| ((i * 53) % 256) as u8 as i8).collect();
+    let b: Vec<i8> = (0..n).map(|j| ((j * 17 + 5) % 256) as u8 as i8).collect();
+    init_int8(a.clone(), b.clone());
+    let reference = |za: i32, zb: i32| -> Vec<i32> {
+        a.chunks(n)
+            .map(|row| {
+                row.iter()
+                    .zip(&b)
+                    .map(|(&a, &b)| (a as i32 - za) * (b as i32 - zb))
+                    .sum()
+            })
+            .collect()
+    };
+
+    for (za, zb) in [(0, 0), (3, -7), (-128, 127), (100, 0)] {
+        set_zero_points(za, zb);
+        assert_eq!(
+            multiply_int8(),
+            reference(za, zb),
+            "za = {}, zb = {}",
+            za,
+            zb
+        );
+    }
+}
+
+#[test]
+fn get_output_follows_the_last_path() {
+    let (n, d) = (9, 4);
+    init(n, d);
+    assert_eq!(last_path(), "heap");
+    multiply_heap().unwrap();
+    let ramp = get_output();
+
+    // Each path sees an updated `b` only from the heap or stable copy it
+    // reads, so every result differs from the last.
+    DATA.with(|data| data.borrow_mut().b = vec![1; n]);
+    multiply_stable().unwrap();
+    assert_eq!(last_path(), "stable");
+    assert_eq!(get_output(), ramp);
+
+    multiply_heap().unwrap();
+    assert_eq!(last_path(), "heap");
+    let ones = get_output();
+    assert_ne!(ones, ramp);
+
+    fill_b(2);
+    multiply_stable_saturating();
+    assert_eq!(last_path(), "stable");
+    assert_eq!(get_output(), ones.iter().map(|x| 2 * x).collect::<Vec<_>>());
+
+    set_output(vec![7; d]);
+    assert_eq!((last_path().as_str(), get_output()), ("heap", vec![7; d]));
+}
+
+#[test]
+fn multiply_heap_mode_applies_each_policy() {
+    // Rows [0, 1], [2, 3] and [4, 5] sum to 1, 5 and 9 times `b`'s value.
+    init(2, 3);
+    fill_b(1000);
+    for mode in [
+        OverflowMode::Wrap,
+        OverflowMode::Saturate,
+        OverflowMode::Checked,
+    ] {
+        assert_eq!(
+            multiply_heap_mode(mode),
+            vec![1000, 5000, 9000],
+            "{:?}",
+            mode
+        );
+        assert_eq!(get_output(), vec![1000, 5000, 9000]);
+    }
+
+    fill_b(1 << 30);
+    assert_eq!(
+        multiply_heap_mode(OverflowMode::Wrap),
+        vec![1 << 30, (5_i64 << 30) as i32, (9_i64 << 30) as i32]
+    );
+    assert_eq!(
+        multiply_heap_mode(OverflowMode::Saturate),
+        vec![1 << 30, i32::MAX, i32::MAX]
+    );
+    fill_b(-(1 << 30));
+    assert_eq!(
+        multiply_heap_mode(OverflowMode::Saturate),
+        vec![-(1 << 30), i32::MIN, i32::MIN]
+    );
+
+    // The exact sum, 2^63, overflows even an `i64`.
+    configure(1, vec![i32::MIN; 2], vec![i32::MIN; 2]);
+    assert_eq!(multiply_heap_mode(OverflowMode::Wrap), vec![0]);
+    assert_eq!(multiply_heap_mode(OverflowMode::Saturate), vec![i32::MAX]);
+}
+
+#[test]
+#[should_panic(expected = "output 9223372036854775808 overflows i32")]
+fn multiply_heap_mode_checked_traps_when_the_sum_overflows_i64() {
+    configure(1, vec![i32::MIN; 2], vec![i32::MIN; 2]);
+    multiply_heap_mode(OverflowMode::Checked);
+}
+
+#[test]
+#[should_panic(expected = "output 5368709120 overflows i32")]
+fn multiply_heap_mode_checked_traps_on_overflow() {
+    init(2, 3);
+    fill_b(1 << 30);
+    multiply_heap_mode(OverflowMode::Checked);
+}
+
+#[test]
+fn export_test_vector_reproduces_after_reimport() {
+    init(11, 5);
+    reseed(21);
+    set_bias(vec![1; 5]);
+    let vector = export_test_vector();
+    assert_eq!((vector.n, vector.d), (11, 5));
+
+    init(1, 1);
+    configure(vector.d as usize, vector.a.clone(), vector.b.clone());
+    multiply_heap().unwrap();
+    assert_eq!(get_output(), vector.expected_out);
+    multiply_stable().unwrap();
+    assert_eq!(get_output(), vector.expected_out);
+    assert_eq!(export_test_vector(), vector);
+}
+
+#[test]
+fn multiply_heap_progressive_walks_through_every_row() {
+    let (n, d) = (10, 11);
+    init(n, d);
+    reseed(17);
+    set_bias((0..d as i32).collect());
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    let mut batches = Vec::new();
+    while batches.iter().map(Vec::len).sum::<usize>() < d {
+        batches.push(multiply_heap_progressive(4));
+    }
+    assert_eq!(
+        batches.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![4, 4, 3]
+    );
+    assert_eq!(batches.concat(), expected);
+
+    // Changing `b` partway starts over.
+    assert_eq!(multiply_heap_progressive(5), expected[..5]);
+    fill_b(1);
+    let restarted = multiply_heap_progressive(d as u64);
+    multiply_heap().unwrap();
+    assert_eq!(restarted, get_output());
+}
+
+#[test]
+fn multiply_heap_pooled_averages_groups() {
+    let (n, d) = (6, 12);
+    init(n, d);
+    reseed(8);
+    multiply_heap().unwrap();
+    let out = get_output();
+    for pool in [1, 3, 4, 12] {
+        let expected: Vec<i32> = out
+            .chunks(pool)
+            .map(|group| (group.iter().map(|&x| x as i64).sum::<i64>() / pool as i64) as i32)
+            .collect();
+        assert_eq!(
+            multiply_heap_pooled(pool as u64),
+            expected,
+            "pool = {}",
+            pool
+        );
+        assert_eq!(get_output(), out);
+    }
+
+    // The sums don't overflow `i32`.
+    configure(2, vec![1; 4], vec![i32::MAX / 2 + 1; 2]);
+    assert_eq!(multiply_heap_pooled(2), vec![i32::MIN]);
+    set_b(vec![3, 4]);
+    set_bias(vec![0, -2]);
+    assert_eq!(multiply_heap_pooled(2), vec![6]);
+    // 7 and -10 average to -1.5, which truncates to -1.
+    set_bias(vec![0, -17]);
+    assert_eq!(multiply_heap_pooled(2), vec![-1]);
+}
+
+#[test]
+#[should_panic(expected = "pool = 5 must divide d = 12")]
+fn multiply_heap_pooled_rejects_uneven_pools() {
+    init(6, 12);
+    multiply_heap_pooled(5);
+}
+
+#[test]
+fn multiply_heap_cancellable_stops_between_rows() {
+    let (n, d) = (8, 6);
+    init(n, d);
+    multiply_heap().unwrap();
+    let before = get_output();
+    fill_b(3);
+
+    CANCEL_AT_ROW.with(|row| row.set(Some(4)));
+    assert_eq!(multiply_heap_cancellable(), 4);
+    // The partial result isn't published.
+    assert_eq!(get_output(), before);
+
+    CANCEL_AT_ROW.with(|row| row.set(None));
+    assert_eq!(multiply_heap_cancellable(), d as u64);
+    let after = get_output();
+    multiply_heap().unwrap();
+    assert_eq!(after, get_output());
+
+    // A cancel with nothing running stops the next multiply before any row.
+    cancel();
+    assert_eq!(multiply_heap_cancellable(), 0);
+    assert_eq!(multiply_heap_cancellable(), d as u64);
+}
+
+#[test]
+fn fingerprint_tracks_shape_and_data() {
+    init(6, 4);
+    reseed(5);
+    let original = fingerprint();
+    assert_eq!(fingerprint(), original);
+    // Rebuilding the same matrix gives the same fingerprint.
+    init(2, 2);
+    init(6, 4);
+    reseed(5);
+    assert_eq!(fingerprint(), original);
+
+    let (a, b) = DATA.with(|data| (data.borrow().a.clone(), data.borrow().b.clone()));
+    for index in [0, 7, a.len() - 1] {
+        let mut changed = a.clone();
+        changed[index] ^= 1;
+        configure(4, changed, b.clone());
+        assert_ne!(fingerprint(), original, "A[{}]", index);
+    }
+    let mut changed = b.clone();
+    changed[5] = changed[5].wrapping_add(1);
+    configure(4, a.clone(), changed);
+    assert_ne!(fingerprint(), original);
+
+    // The same elements in another shape differ too: 6 rows of 3 then 3,
+    // against 2 rows of 7 then 7.
+    let elems = &a[..21];
+    configure(6, elems[..18].to_vec(), elems[18..].to_vec());
+    let tall = fingerprint();
+    configure(2, elems[..14].to_vec(), elems[14..].to_vec());
+    assert_ne!(fingerprint(), tall);
+}
+
+#[test]
+fn write_output_to_a_column_places_one_output_per_row() {
+    let (n, d) = (5, 3);
+    init(n, d);
+    multiply_heap().unwrap();
+    let out = get_output();
+    let rows: Vec<Vec<i32>> = (0..d as u32).map(get_a_row).collect();
+
+    write_output_to_a_column(2);
+    for (i, (row, &val)) in rows.into_iter().zip(&out).enumerate() {
+        let mut expected = row;
+        expected[2] = val;
+        assert_eq!(get_a_row(i as u32), expected, "row {}", i);
+        assert_eq!(
+            read_stable_i32s(Layout::current().a_addr() + (i * n * 4) as u64, n),
+            expected
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "column 5 is out of range for n = 5")]
+fn write_output_to_a_column_rejects_out_of_range_columns() {
+    init(5, 3);
+    write_output_to_a_column(5);
+}
+
+#[test]
+fn layout_alignment_flags_misaligned_regions() {
+    let region = |name: &str| {
+        layout_alignment()
+            .into_iter()
+            .find(|(region, _, _)| region == name)
+            .unwrap()
+    };
+
+    // `b` starts at `n * d * 4`, so a multiple of 4 for `n` keeps it aligned.
+    init(8, 3);
+    assert_eq!(region("a"), ("a".to_string(), 0, 0));
+    assert_eq!(region("b"), ("b".to_string(), 96, 0));
+    assert_eq!(region("out"), ("out".to_string(), 128, 0));
+
+    init(5, 3);
+    assert_eq!(region("b"), ("b".to_string(), 60, 12));
+    // The 5 elements of `b` happen to realign `out`.
+    assert_eq!(region("out"), ("out".to_string(), 80, 0));
+    assert_eq!(layout_alignment().len(), Layout::current().regions().len());
+}
+
+#[test]
+fn multiply_heap_signs_matches_output_signs() {
+    init(4, 6);
+    set_b(vec![1, -1, 0, 0]);
+    // Each row's first two elements are consecutive, so every output is -1:
+    // bias it to each sign in turn.
+    set_bias(vec![0, 1, 2, -5, i32::MAX, i32::MIN + 1]);
+    let signs = multiply_heap_signs();
+    assert_eq!(signs, vec![-1, 0, 1, -1, 1, -1]);
+    let expected: Vec<i8> = get_output().iter().map(|val| val.signum() as i8).collect();
+    assert_eq!(signs, expected);
+}
+
+#[test]
+fn multiply_ternary_matches_multiply_heap() {
+    let (n, d) = (13, 5);
+    let a: Vec<i32> = (0..n * d).map(|k| ((k * 7) % 3) as i32 - 1).collect();
+    configure(d, a, (0..n as i32).map(|j| j * j - 40).collect());
+    set_bias(vec![3, -3, 0, 1, i32::MAX]);
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    pack_ternary_a();
+    set_output(vec![0; d]);
+    multiply_ternary().unwrap();
+    assert_eq!(get_output(), expected);
+    TERNARY_A.with(|ternary| assert_eq!(ternary.borrow().packed.len(), (n * d).div_ceil(4)));
+}
+
+#[test]
+#[should_panic(expected = "A[2] = 2 is not ternary")]
+fn pack_ternary_a_rejects_other_weights() {
+    init(4, 2);
+    pack_ternary_a();
+}
+
+#[test]
+fn multiply_heap_with_argmax_matches_a_separate_scan() {
+    configure(
+        4,
+        vec![1, 2, -3, 4, 5, -6, 1, 2, -3, 0, 0, 7],
+        vec![2, -1, 3],
+    );
+    set_bias(vec![0, 0, 0, -12]);
+    let (out, argmax) = multiply_heap_with_argmax();
+    assert_eq!(out, vec![-9, -15, -9, 9]);
+    assert_eq!(out, get_output());
+    let scanned = (0..out.len()).fold(0, |best, i| if out[i] > out[best] { i } else { best });
+    assert_eq!(argmax, Some(scanned as u64));
+
+    // Ties go to the first index, even with every output `i32::MIN`.
+    configure(3, vec![0; 6], vec![1, 1]);
+    set_bias(vec![i32::MIN; 3]);
+    assert_eq!(multiply_heap_with_argmax(), (vec![i32::MIN; 3], Some(0)));
+}
+
+#[test]
+fn multiply_rle_matches_multiply_heap() {
+    let (n, d) = (7, 5);
+    // Runs of up to 12 repeated values, some crossing rows or spanning one.
+    let runs: Vec<(i32, u64)> = vec![(3, 9), (-2, 4), (0, 1), (i32::MAX, 7), (5, 2), (-1, 12)];
+    let a: Vec<i32> = runs
+        .iter()
+        .flat_map(|&(value, count)| std::iter::repeat_n(value, count as usize))
+        .collect();
+    configure(d, a, (0..n).map(|j| j * 3 - 8).collect());
+    set_bias(vec![1, -1, 0, 100, i32::MIN]);
+    multiply_heap().unwrap();
+    let expected = get_output();
+
+    let used = memory_stats().stable_used_bytes;
+    init_rle(runs);
+    assert_eq!(memory_stats().stable_used_bytes, used + 6 * 12);
+    set_output(vec![0; d]);
+    multiply_rle().unwrap();
+    assert_eq!(get_output(), expected);
+}
+
+#[test]
+#[should_panic(expected = "the runs must cover n * d = 8 elements, got Some(7)")]
+fn init_rle_rejects_runs_of_the_wrong_length() {
+    init(4, 2);
+    init_rle(vec![(1, 3), (2, 4)]);
+}
+
+/// Runs `multiply_stable` for a `d × n` matrix for each of `ns` in turn, with
+/// `b` read from stable memory, prepared by `prepare_b_simd` and read by the
+/// scalar loop, and compares each against a scalar reference. Returns a
+/// description of the first mismatch.
+#[cfg(test)]
+fn first_stable_mismatch(ns: impl IntoIterator<Item = usize>, d: usize) -> Option<String> {
+    for n in ns {
+        let a: Vec<i32> = (0..(n * d) as i32)
+            .map(|x| (x * 7919) % 2001 - 1000)
+            .collect();
+        // Large enough for some dot products to overflow `i32`.
+        let b: Vec<i32> = (0..n as i32).map(|x| (x - 3) * 0x0100_0001).collect();
+        let bias: Vec<i32> = (0..d as i32).map(|i| 17 * i - 20).collect();
+        let expected: Vec<i32> = (0..d)
+            .map(|i| {
+                a[i * n..(i + 1) * n]
+                    .iter()
+                    .zip(&b)
+                    .fold(bias[i], |acc, (&a, &b)| acc.wrapping_add(a.wrapping_mul(b)))
+            })
+            .collect();
+
+        configure(d, a, b);
+        set_bias(bias);
+        let out_addr = Layout::current().out_addr();
+        for path in ["unprepared", "prepared", "scalar"] {
+            match path {
+                "prepared" => prepare_b_simd(),
+                "scalar" => set_simd_threshold(usize::MAX),
+                _ => {}
+            }
+            multiply_stable().unwrap();
+            let actual = read_stable_i32s(out_addr, d);
+            if actual != expected {
+                return Some(format!(
+                    "n = {} (n % 4 = {}), {} b: got {:?}, expected {:?}",
+                    n,
+                    n % 4,
+                    path,
+                    actual,
+                    expected
+                ));
+            }
+        }
+        set_simd_threshold(0);
+    }
+    None
+}
+
+#[test]
+fn multiply_stable_matches_scalar_for_every_remainder() {
+    for d in [1, 3, 4] {
+        if let Some(mismatch) = first_stable_mismatch(0..=67, d) {
+            panic!("d = {}: {}", d, mismatch);
+        }
+    }
+}
+
+#[test]
+fn output_cursor_reads_the_stable_output_after_a_stable_multiply() {
+    init(4, OUTPUT_CURSOR_BATCH + 5);
+    multiply_stable().unwrap();
+
+    let (first, token) = output_cursor(0);
+    let (rest, next) = output_cursor(token);
+    assert_eq!(next, 0);
+    assert_eq!([first, rest].concat(), get_output());
+}
+
+#[test]
+#[should_panic(expected = "the output has changed since this cursor started")]
+fn output_cursor_traps_when_the_output_changes_between_batches() {
+    init(4, OUTPUT_CURSOR_BATCH + 5);
+    multiply_heap().unwrap();
+
+    let (_, token) = output_cursor(0);
+    multiply_heap().unwrap();
+    output_cursor(token);
+}
+
+#[test]
+#[should_panic(expected = "overlaps the saved matrices and runs starting at")]
+fn multiply_stable_to_refuses_the_run_length_encoded_a() {
+    init(4, 2);
+    init_rle(vec![(1, 8)]);
+    multiply_stable_to(Layout::current().size()).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "n = 4294967296, d = 4294967296 is too large")]
+fn multiply_pure_stable_rejects_overflowing_shapes() {
+    multiply_pure_stable(1 << 32, 1 << 32).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "would share stable memory with the named matrices or runs")]
+fn multiply_pure_stable_refuses_the_named_matrices() {
+    init(4, 2);
+    save_matrix("first".to_string());
+    multiply_pure_stable(64, 8).unwrap();
+}
+
+#[test]
+fn multiply_pure_stable_with_the_current_shape_becomes_the_output() {
+    init(8, 4);
+    multiply_heap().unwrap();
+    let expected = get_output();
+    set_output(vec![0; 4]);
+
+    multiply_pure_stable(8, 4).unwrap();
+    assert_eq!(get_output(), expected);
+}
+
+#[test]
+#[should_panic(expected = "strided b ends past byte 18446744073709551615")]
+fn multiply_stable_strided_rejects_overflowing_strides() {
+    init(4, 2);
+    multiply_stable_strided(u64::MAX / 2).unwrap();
+}
+
+#[test]
+fn multiply_stable_old_wraps_and_adds_the_bias_like_multiply_heap() {
+    let (n, d) = (8, 3);
+    configure(
+        d,
+        (0..(n * d) as i32).map(|x| i32::MAX - x).collect(),
+        (0..n as i32).map(|x| x + 2).collect(),
+    );
+    set_bias(vec![i32::MAX, -5, i32::MIN]);
+
+    multiply_heap().unwrap();
+    let expected = get_output();
+    multiply_stable_old().unwrap();
+    assert_eq!(get_output(), expected);
+}
+
+#[test]
+#[should_panic(expected = "is too large")]
+fn init_f32_rejects_overflowing_shapes() {
+    init_f32(usize::MAX / 2, 3);
+}
+
+#[test]
+#[should_panic(expected = "is too large")]
+fn init_i64_rejects_overflowing_shapes() {
+    init_i64(1 << 32, 1 << 32);
 }
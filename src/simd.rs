@@ -0,0 +1,203 @@
+//! The `core::arch::wasm32` SIMD intrinsics used by the stable multiplies.
+//!
+//! On wasm these are the real intrinsics. Natively they are emulated lane by
+//! lane, so that the same kernels can be run (slowly) under `cargo test`.
+
+#[cfg(target_arch = "wasm32")]
+pub use core::arch::wasm32::*;
+
+/// An integer element type whose `v128` holds `LANES` elements, and which
+/// knows how to multiply-accumulate a vector of them into an `i32x4`.
+///
+/// The stable multiply kernel is generic over this, so `i32x4`, `i16x8` and
+/// `i8x16` inputs share the same loop.
+pub trait SimdLanes<const LANES: usize> {
+    /// Size of one element in bytes.
+    const BYTES: usize = 16 / LANES;
+
+    /// Multiplies `a` and `b` lane-wise and adds the products into the
+    /// `i32x4` accumulator `acc`, without overflowing the element type.
+    fn mul_acc(acc: v128, a: v128, b: v128) -> v128;
+
+    /// Decodes one little-endian element, for the tail that doesn't fill a
+    /// whole `v128`.
+    fn from_le_bytes(bytes: &[u8]) -> i32;
+}
+
+impl SimdLanes<4> for i32 {
+    fn mul_acc(acc: v128, a: v128, b: v128) -> v128 {
+        i32x4_add(acc, i32x4_mul(a, b))
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> i32 {
+        i32::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
+
+impl SimdLanes<8> for i16 {
+    fn mul_acc(acc: v128, a: v128, b: v128) -> v128 {
+        // Multiplies into `i32`s and sums adjacent pairs.
+        i32x4_add(acc, i32x4_dot_i16x8(a, b))
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> i32 {
+        i16::from_le_bytes(bytes.try_into().unwrap()) as i32
+    }
+}
+
+impl SimdLanes<16> for i8 {
+    fn mul_acc(acc: v128, a: v128, b: v128) -> v128 {
+        // The product of two `i8`s always fits in an `i16`.
+        let low = i16x8_extmul_low_i8x16(a, b);
+        let high = i16x8_extmul_high_i8x16(a, b);
+        i32x4_add(
+            acc,
+            i32x4_add(
+                i32x4_extadd_pairwise_i16x8(low),
+                i32x4_extadd_pairwise_i16x8(high),
+            ),
+        )
+    }
+
+    fn from_le_bytes(bytes: &[u8]) -> i32 {
+        i8::from_le_bytes(bytes.try_into().unwrap()) as i32
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use emulated::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(non_camel_case_types)]
+mod emulated {
+    /// A 128-bit vector holding its lanes as little-endian bytes, like wasm's
+    /// `v128`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct v128([u8; 16]);
+
+    impl v128 {
+        fn i32_lanes(self) -> [i32; 4] {
+            std::array::from_fn(|i| {
+                i32::from_le_bytes(self.0[i * 4..i * 4 + 4].try_into().unwrap())
+            })
+        }
+
+        fn from_i32_lanes(lanes: [i32; 4]) -> Self {
+            let mut bytes = [0; 16];
+            for (chunk, lane) in bytes.chunks_exact_mut(4).zip(lanes) {
+                chunk.copy_from_slice(&lane.to_le_bytes());
+            }
+            Self(bytes)
+        }
+
+        fn i64_lanes(self) -> [i64; 2] {
+            std::array::from_fn(|i| {
+                i64::from_le_bytes(self.0[i * 8..i * 8 + 8].try_into().unwrap())
+            })
+        }
+
+        fn from_i64_lanes(lanes: [i64; 2]) -> Self {
+            let mut bytes = [0; 16];
+            for (chunk, lane) in bytes.chunks_exact_mut(8).zip(lanes) {
+                chunk.copy_from_slice(&lane.to_le_bytes());
+            }
+            Self(bytes)
+        }
+
+        fn i16_lanes(self) -> [i16; 8] {
+            std::array::from_fn(|i| i16::from_le_bytes([self.0[i * 2], self.0[i * 2 + 1]]))
+        }
+
+        fn from_i16_lanes(lanes: [i16; 8]) -> Self {
+            let mut bytes = [0; 16];
+            for (chunk, lane) in bytes.chunks_exact_mut(2).zip(lanes) {
+                chunk.copy_from_slice(&lane.to_le_bytes());
+            }
+            Self(bytes)
+        }
+
+        fn i8_lanes(self) -> [i8; 16] {
+            self.0.map(|byte| byte as i8)
+        }
+
+        fn map_i32(self, other: Self, f: impl Fn(i32, i32) -> i32) -> Self {
+            let (a, b) = (self.i32_lanes(), other.i32_lanes());
+            Self::from_i32_lanes(std::array::from_fn(|i| f(a[i], b[i])))
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `m` must point to 16 readable bytes. It needn't be aligned.
+    pub unsafe fn v128_load(m: *const v128) -> v128 {
+        m.read_unaligned()
+    }
+
+    pub fn i32x4(a0: i32, a1: i32, a2: i32, a3: i32) -> v128 {
+        v128::from_i32_lanes([a0, a1, a2, a3])
+    }
+
+    pub fn i32x4_add(a: v128, b: v128) -> v128 {
+        a.map_i32(b, i32::wrapping_add)
+    }
+
+    pub fn i32x4_mul(a: v128, b: v128) -> v128 {
+        a.map_i32(b, i32::wrapping_mul)
+    }
+
+    pub fn i32x4_max(a: v128, b: v128) -> v128 {
+        a.map_i32(b, i32::max)
+    }
+
+    pub fn i32x4_extract_lane<const N: usize>(a: v128) -> i32 {
+        a.i32_lanes()[N]
+    }
+
+    pub fn i64x2(a0: i64, a1: i64) -> v128 {
+        v128::from_i64_lanes([a0, a1])
+    }
+
+    pub fn i64x2_add(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i64_lanes(), b.i64_lanes());
+        v128::from_i64_lanes(std::array::from_fn(|i| a[i].wrapping_add(b[i])))
+    }
+
+    pub fn i64x2_extract_lane<const N: usize>(a: v128) -> i64 {
+        a.i64_lanes()[N]
+    }
+
+    pub fn i64x2_extmul_low_i32x4(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i32_lanes(), b.i32_lanes());
+        v128::from_i64_lanes(std::array::from_fn(|i| a[i] as i64 * b[i] as i64))
+    }
+
+    pub fn i64x2_extmul_high_i32x4(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i32_lanes(), b.i32_lanes());
+        v128::from_i64_lanes(std::array::from_fn(|i| a[i + 2] as i64 * b[i + 2] as i64))
+    }
+
+    pub fn i32x4_dot_i16x8(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i16_lanes(), b.i16_lanes());
+        let product = |i: usize| a[i] as i32 * b[i] as i32;
+        v128::from_i32_lanes(std::array::from_fn(|i| {
+            product(2 * i).wrapping_add(product(2 * i + 1))
+        }))
+    }
+
+    pub fn i16x8_extmul_low_i8x16(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i8_lanes(), b.i8_lanes());
+        v128::from_i16_lanes(std::array::from_fn(|i| a[i] as i16 * b[i] as i16))
+    }
+
+    pub fn i16x8_extmul_high_i8x16(a: v128, b: v128) -> v128 {
+        let (a, b) = (a.i8_lanes(), b.i8_lanes());
+        v128::from_i16_lanes(std::array::from_fn(|i| a[i + 8] as i16 * b[i + 8] as i16))
+    }
+
+    pub fn i32x4_extadd_pairwise_i16x8(a: v128) -> v128 {
+        let a = a.i16_lanes();
+        v128::from_i32_lanes(std::array::from_fn(|i| {
+            a[2 * i] as i32 + a[2 * i + 1] as i32
+        }))
+    }
+}
@@ -0,0 +1,134 @@
+//! A first-fit allocator for regions of stable memory, giving each named
+//! matrix its own region.
+//!
+//! Freed regions go on a free list, merged with their neighbours, and are
+//! reused before the allocator bumps its end further out. It only hands out
+//! offsets: growing stable memory to cover them is up to the caller.
+
+/// The free list and end of the allocated regions.
+pub struct StableAlloc {
+    /// Free `(start, len)` regions below `end`, sorted by `start` and never
+    /// adjacent to one another.
+    free: Vec<(u64, u64)>,
+    /// One past the highest byte ever allocated.
+    end: u64,
+}
+
+impl StableAlloc {
+    pub const fn new() -> Self {
+        Self {
+            free: Vec::new(),
+            end: 0,
+        }
+    }
+
+    /// One past the highest byte ever allocated.
+    #[cfg(test)]
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Allocates `len` bytes starting at or after `floor`, below which
+    /// stable memory is in use by something else. Takes the first free region
+    /// with room, or else extends the end.
+    pub fn alloc(&mut self, len: u64, floor: u64) -> u64 {
+        for index in 0..self.free.len() {
+            let (start, free_len) = self.free[index];
+            let usable = start.max(floor);
+            let end = start + free_len;
+            if usable + len > end {
+                continue;
+            }
+            self.free.remove(index);
+            // Whatever is left on either side stays free.
+            if usable > start {
+                self.free.insert(index, (start, usable - start));
+            }
+            if usable + len < end {
+                self.insert_free(usable + len, end - usable - len);
+            }
+            return usable;
+        }
+        let start = self.end.max(floor);
+        self.end = start + len;
+        start
+    }
+
+    /// Returns the `len` bytes at `start`, which must have come from `alloc`,
+    /// to the free list.
+    pub fn free(&mut self, start: u64, len: u64) {
+        if len > 0 {
+            self.insert_free(start, len);
+        }
+    }
+
+    fn insert_free(&mut self, start: u64, len: u64) {
+        let index = self.free.partition_point(|&(other, _)| other < start);
+        self.free.insert(index, (start, len));
+        // Merge with the following, then the preceding region.
+        if let Some(&(next, next_len)) = self.free.get(index + 1) {
+            if start + len == next {
+                self.free[index].1 += next_len;
+                self.free.remove(index + 1);
+            }
+        }
+        if index > 0 {
+            let (prev, prev_len) = self.free[index - 1];
+            if prev + prev_len == start {
+                self.free[index - 1].1 += self.free[index].1;
+                self.free.remove(index);
+            }
+        }
+    }
+}
+
+#[test]
+fn alloc_bumps_past_the_floor() {
+    let mut alloc = StableAlloc::new();
+    assert_eq!(alloc.alloc(100, 64), 64);
+    assert_eq!(alloc.alloc(20, 64), 164);
+    assert_eq!(alloc.alloc(8, 1000), 1000);
+    assert_eq!(alloc.end(), 1008);
+}
+
+#[test]
+fn freed_regions_are_reused() {
+    let mut alloc = StableAlloc::new();
+    let first = alloc.alloc(100, 0);
+    let second = alloc.alloc(100, 0);
+    alloc.free(first, 100);
+
+    // A region of the same size, or smaller, goes where `first` was.
+    assert_eq!(alloc.alloc(100, 0), first);
+    alloc.free(first, 100);
+    assert_eq!(alloc.alloc(60, 0), first);
+    assert_eq!(alloc.alloc(40, 0), first + 60);
+    // A larger one doesn't fit in any freed region.
+    alloc.free(first, 60);
+    assert_eq!(alloc.alloc(160, 0), second + 100);
+    assert_eq!(alloc.end(), second + 260);
+}
+
+#[test]
+fn adjacent_free_regions_merge() {
+    let mut alloc = StableAlloc::new();
+    let regions: Vec<u64> = (0..3).map(|_| alloc.alloc(10, 0)).collect();
+    alloc.free(regions[0], 10);
+    alloc.free(regions[2], 10);
+    alloc.free(regions[1], 10);
+    assert_eq!(alloc.free, vec![(0, 30)]);
+    assert_eq!(alloc.alloc(30, 0), 0);
+    assert_eq!(alloc.end(), 30);
+}
+
+#[test]
+fn free_regions_below_the_floor_are_skipped() {
+    let mut alloc = StableAlloc::new();
+    let first = alloc.alloc(100, 0);
+    alloc.alloc(10, 0);
+    alloc.free(first, 100);
+    // Only the part of the freed region past the floor is usable.
+    assert_eq!(alloc.alloc(40, 50), 50);
+    assert_eq!(alloc.free, vec![(0, 50), (90, 10)]);
+    assert_eq!(alloc.alloc(40, 80), 110);
+}